@@ -1,11 +1,12 @@
 use anyhow::{anyhow, Error};
 use argh::FromArgs;
-use chrono::Local;
-use exif::{In, Reader, Tag};
+use chrono::{Local, Timelike};
+use exif::{Exif, In, Reader, Tag, Value};
 use indicatif::{ProgressBar, ProgressState, ProgressStyle};
 use log::{info, warn};
-use regex::Regex;
+use rayon::prelude::*;
 use rusqlite::{Connection, Result};
+use serde_derive::Serialize;
 use simplelog::{Config, LevelFilter, SimpleLogger};
 use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
@@ -16,13 +17,36 @@ use std::io::Write as IoWrite;
 use std::ops::Add;
 use std::path::{Path, PathBuf, MAIN_SEPARATOR_STR};
 use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
 use walkdir::{DirEntry, WalkDir};
 
-use photo_renamer::config::RenamerConfig;
+use photo_renamer::config::{RenamerConfig, TransferMode};
+use photo_renamer::db;
+use photo_renamer::directory::{self, DirectoryPattern};
+use photo_renamer::filename::{self, FilenamePattern};
+use photo_renamer::group::GroupKey;
+use photo_renamer::heif;
+use photo_renamer::live_photo;
+use photo_renamer::motion_photo::{self, EmbeddedVideo, MotionPhotoMode};
+use photo_renamer::quicktime;
+use photo_renamer::raw;
+use photo_renamer::template::TemplateValues;
+use photo_renamer::timestamp::{self, CaptureTime, DateSource, ExifDateField};
 
 pub const SUPPORTED_EXIF_EXTENSIONS: [&str; 3] = ["jpg", "tiff", "jpeg"];
-pub const SUPPORTED_RAW_EXTENSIONS: [&str; 3] = ["dng", "rw2", "raw"];
+pub const SUPPORTED_HEIF_EXTENSIONS: [&str; 4] = ["heic", "heif", "hif", "avif"];
+pub const SUPPORTED_RAW_EXTENSIONS: [&str; 9] = [
+    "dng", "rw2", "raw", "cr2", "cr3", "nef", "arw", "orf", "raf",
+];
+/// Operation recorded in the journal for files skipped as duplicates, which have no output to undo.
+const DUPLICATE_OPERATION: &str = "duplicate";
+/// Operation recorded in the journal for videos extracted from motion photos, which are removed on undo.
+const EXTRACT_OPERATION: &str = "extract";
+
 pub const SUPPORTED_MOVIE_EXTENSIONS: [&str; 4] = ["mp4", "avi", "mpg", "mov"];
+/// Files written alongside pictures and movies by cameras and editors, which follow their primary file
+pub const SUPPORTED_SIDECAR_EXTENSIONS: [&str; 5] = ["xmp", "aae", "thm", "pp3", "dop"];
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// Processes a collection of photos and videos, copying them to an output folder with a standardised
@@ -44,12 +68,25 @@ struct RenamerArgs {
 enum SubCommandEnum {
     Rename(RenameSubCommand),
     Rebase(RebaseSubCommand),
+    Undo(UndoSubCommand),
+    Verify(VerifySubCommand),
+    Report(ReportSubCommand),
+    Explain(ExplainSubCommand),
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// rename and copy files as per the config file
 #[argh(subcommand, name = "rename")]
-struct RenameSubCommand {}
+struct RenameSubCommand {
+    #[argh(option)]
+    /// how files are transferred to the output folders (copy, move, hardlink, symlink or reflink), overriding
+    /// the config file
+    transfer_mode: Option<TransferMode>,
+
+    #[argh(option, short = 'j')]
+    /// number of files to process concurrently, defaulting to the number of CPUs
+    jobs: Option<usize>,
+}
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// update the source file locations in the db to allow for file-system changes
@@ -64,34 +101,82 @@ struct RebaseSubCommand {
     destination_file_root: String,
 }
 
+#[derive(FromArgs, PartialEq, Debug)]
+/// reverse the transfers made by a run, removing or restoring its output files and forgetting its history
+#[argh(subcommand, name = "undo")]
+struct UndoSubCommand {
+    #[argh(positional)]
+    /// the id of the run to undo, as logged at the end of each rename, defaulting to the most recent run
+    run_id: Option<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// check that every file in the history still exists, and that outputs still match their checksums
+#[argh(subcommand, name = "verify")]
+struct VerifySubCommand {
+    #[argh(option)]
+    /// file to write the JSON lines report to, defaulting to a timestamped file in the current directory
+    report: Option<String>,
+
+    #[argh(switch)]
+    /// include files without problems in the report
+    all: bool,
+
+    #[argh(option, short = 'j')]
+    /// number of files to check concurrently, defaulting to the number of CPUs
+    jobs: Option<usize>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// list the files in the history whose date came from a given source, by default their modified time
+#[argh(subcommand, name = "report")]
+struct ReportSubCommand {
+    #[argh(option, default = "DateSource::Mtime")]
+    /// the date source to report on: metadata, filename, directory or mtime
+    date_source: DateSource,
+
+    #[argh(option)]
+    /// file to write the JSON lines report to, defaulting to a timestamped file in the current directory
+    output: Option<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// show how the date and output name of a single file are worked out
+#[argh(subcommand, name = "explain")]
+struct ExplainSubCommand {
+    #[argh(positional)]
+    /// the file to explain
+    path: String,
+}
+
 /// Return a valid database connection to a local SQLlite DB, with the name specified by the
-/// arguments to the CLI.
+/// arguments to the CLI. The schema is migrated to the latest version if needed.
 fn get_db(args: &RenamerArgs) -> Result<Connection, Error> {
-    let db_path = Path::new(&args.db_name);
-
-    let exists = db_path.exists();
+    db::open(Path::new(&args.db_name))
+}
 
-    let db_connection = Connection::open(db_path)?;
+fn _is_picture(file: &Path) -> bool {
+    if let Some(extension_str) = file.extension() {
+        let extension_str = extension_str.to_str().unwrap().to_lowercase();
 
-    if !exists {
-        db_connection.execute("CREATE TABLE files (filename TEXT, checksum TEXT)", ())?;
-        db_connection.execute("CREATE UNIQUE INDEX unique_paths ON files (filename)", ())?;
+        return SUPPORTED_EXIF_EXTENSIONS.contains(&extension_str.as_str())
+            || SUPPORTED_HEIF_EXTENSIONS.contains(&extension_str.as_str());
     }
 
-    Ok(db_connection)
+    false
 }
 
-fn _is_picture(file: &PathBuf) -> bool {
+fn _is_heif(file: &Path) -> bool {
     if let Some(extension_str) = file.extension() {
         let extension_str = extension_str.to_str().unwrap().to_lowercase();
 
-        return SUPPORTED_EXIF_EXTENSIONS.contains(&extension_str.as_str());
+        return SUPPORTED_HEIF_EXTENSIONS.contains(&extension_str.as_str());
     }
 
     false
 }
 
-fn _is_raw(file: &PathBuf) -> bool {
+fn _is_raw(file: &Path) -> bool {
     if let Some(extension_str) = file.extension() {
         let extension_str = extension_str.to_str().unwrap().to_lowercase();
 
@@ -101,7 +186,7 @@ fn _is_raw(file: &PathBuf) -> bool {
     false
 }
 
-fn _is_movie(file: &PathBuf) -> bool {
+fn _is_movie(file: &Path) -> bool {
     if let Some(extension_str) = file.extension() {
         let extension_str = extension_str.to_str().unwrap().to_lowercase();
 
@@ -111,8 +196,23 @@ fn _is_movie(file: &PathBuf) -> bool {
     false
 }
 
-fn file_in_scope(file: &PathBuf) -> bool {
-    _is_picture(&file) || _is_raw(&file) || _is_movie(&file)
+fn _is_sidecar(file: &Path) -> bool {
+    if let Some(extension_str) = file.extension() {
+        let extension_str = extension_str.to_str().unwrap().to_lowercase();
+
+        return SUPPORTED_SIDECAR_EXTENSIONS.contains(&extension_str.as_str());
+    }
+
+    false
+}
+
+fn _is_live_photo_video(file: &Path) -> bool {
+    file.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("mov"))
+}
+
+fn file_in_scope(file: &Path) -> bool {
+    _is_picture(file) || _is_raw(file) || _is_movie(file)
 }
 
 fn _is_hidden(entry: &DirEntry) -> bool {
@@ -123,14 +223,14 @@ fn _is_hidden(entry: &DirEntry) -> bool {
         .unwrap_or(false)
 }
 
-/// Return all filenames that will be considered for processing. This means we will exclude any filenames
-/// matching any exclusions from the config
+/// Return all filenames that will be considered for processing, grouped according to the configured grouping
+/// rule. This means we will exclude any filenames matching any exclusions from the config
 fn get_all_filenames_in_scope(
     config: &RenamerConfig,
-) -> Result<HashMap<String, Vec<PathBuf>>, Error> {
+) -> Result<HashMap<GroupKey, Vec<PathBuf>>, Error> {
     info!("Determining in-scope filenames");
 
-    let mut filenames: HashMap<String, Vec<PathBuf>> = HashMap::new();
+    let mut filenames: HashMap<GroupKey, Vec<PathBuf>> = HashMap::new();
 
     for root_path_string in &config.root_paths {
         for entry in WalkDir::new(
@@ -152,7 +252,7 @@ fn get_all_filenames_in_scope(
                 .ok_or("Unable to convert path to string")
                 .unwrap();
 
-            if !file_in_scope(&entry_pathbuf) {
+            if !file_in_scope(&entry_pathbuf) && !_is_sidecar(&entry_pathbuf) {
                 continue;
             }
 
@@ -166,40 +266,385 @@ fn get_all_filenames_in_scope(
             }
 
             if !exclusion_found {
+                // Sidecars named after their primary's full name, such as IMG_0001.CR2.xmp, are grouped under the
+                // primary's name
+                let group_path = match entry_pathbuf.with_extension("") {
+                    primary_path if _is_sidecar(&entry_pathbuf) && file_in_scope(&primary_path) => {
+                        primary_path
+                    }
+                    _ => entry_pathbuf.clone(),
+                };
+
                 filenames
-                    .entry(
-                        entry_pathbuf
-                            .file_stem()
-                            .unwrap()
-                            .to_str()
-                            .unwrap()
-                            .to_string(),
-                    )
+                    .entry(GroupKey::new(&group_path, config.grouping).unwrap())
                     .or_insert(vec![])
                     .push(entry_pathbuf);
             }
         }
     }
 
-    info!("Found {} unique file stems", filenames.len());
+    info!("Found {} file groups", filenames.len());
 
     Ok(filenames)
 }
 
+/// Find the root path a file was found under. Where roots are nested, the nearest is used.
+fn find_root_path(file: &Path, config: &RenamerConfig) -> Option<PathBuf> {
+    config
+        .root_paths
+        .iter()
+        .filter_map(|root_path| Path::new(root_path).canonicalize().ok())
+        .filter(|root_path| file.starts_with(root_path))
+        .max_by_key(|root_path| root_path.components().count())
+}
+
+/// Read the identifier Apple writes to both the photo and the video of a Live Photo, from the MakerNote of the
+/// photo or the QuickTime metadata of the video.
+fn extract_content_identifier(file: &Path) -> Option<String> {
+    if _is_live_photo_video(file) {
+        return quicktime::extract_content_identifier(file).ok().flatten();
+    }
+
+    if !_is_picture(file) {
+        return None;
+    }
+
+    let exif_data = read_exif(file).ok()?;
+
+    match &exif_data.get_field(Tag::MakerNote, In::PRIMARY)?.value {
+        Value::Undefined(maker_note, _) => {
+            live_photo::content_identifier_from_maker_note(maker_note)
+        }
+        _ => None,
+    }
+}
+
+/// Live Photo videos are usually named after their photo, so end up in its group, but exports don't always keep
+/// the names in step. Move any video left in a group without a photo into the group of the photo with the same
+/// content identifier. Only pending files are read, as finding the identifiers means parsing every candidate,
+/// and anything already processed has been named.
+fn regroup_live_photo_videos(
+    filenames: &mut HashMap<GroupKey, Vec<PathBuf>>,
+    pending_paths: &HashSet<PathBuf>,
+    thread_pool: &rayon::ThreadPool,
+) {
+    let unpaired_videos = filenames
+        .iter()
+        .filter(|(_, paths)| !paths.iter().any(|path| _is_picture(path)))
+        .flat_map(|(group_key, paths)| {
+            paths
+                .iter()
+                .filter(|path| _is_live_photo_video(path) && pending_paths.contains(*path))
+                .map(move |path| (group_key.clone(), path.clone()))
+        })
+        .collect::<Vec<(GroupKey, PathBuf)>>();
+
+    // Only read the photos if there's a video that might belong to one of them
+    if unpaired_videos.is_empty() {
+        return;
+    }
+
+    let unpaired_videos = thread_pool.install(|| {
+        unpaired_videos
+            .into_par_iter()
+            .filter_map(|(group_key, path)| {
+                Some((extract_content_identifier(&path)?, group_key, path))
+            })
+            .collect::<Vec<(String, GroupKey, PathBuf)>>()
+    });
+
+    // Only read the photos if there's a video that might belong to one of them
+    if unpaired_videos.is_empty() {
+        return;
+    }
+
+    let photos = filenames
+        .iter()
+        .flat_map(|(group_key, paths)| {
+            paths
+                .iter()
+                .filter(|path| _is_picture(path) && pending_paths.contains(*path))
+                .map(move |path| (group_key.clone(), path.clone()))
+        })
+        .collect::<Vec<(GroupKey, PathBuf)>>();
+
+    let photo_groups = thread_pool.install(|| {
+        photos
+            .into_par_iter()
+            .filter_map(|(group_key, path)| Some((extract_content_identifier(&path)?, group_key)))
+            .collect::<HashMap<String, GroupKey>>()
+    });
+
+    for (content_identifier, video_group_key, video) in unpaired_videos {
+        let photo_group_key = match photo_groups.get(&content_identifier) {
+            None => continue,
+            Some(photo_group_key) => photo_group_key,
+        };
+
+        if let Some(paths) = filenames.get_mut(&video_group_key) {
+            paths.retain(|path| path != &video);
+
+            if paths.is_empty() {
+                filenames.remove(&video_group_key);
+            }
+        }
+
+        filenames.get_mut(photo_group_key).unwrap().push(video);
+    }
+}
+
+/// The photo and video making up an Apple Live Photo.
+struct LivePhotoPair {
+    photo: PathBuf,
+    video: PathBuf,
+    /// The identifier they share, or None if they were paired by name
+    content_identifier: Option<String>,
+}
+
+/// Find the Live Photos among the files of a group. Photos and videos are paired by the content identifier
+/// they share where they have one, or failing that, by having the same name in the same directory, as long as
+/// they don't have different identifiers.
+fn find_live_photo_pairs(paths: &[PathBuf]) -> Vec<LivePhotoPair> {
+    let videos = paths
+        .iter()
+        .filter(|path| _is_live_photo_video(path))
+        .map(|path| (path, extract_content_identifier(path)))
+        .collect::<Vec<(&PathBuf, Option<String>)>>();
+
+    if videos.is_empty() {
+        return vec![];
+    }
+
+    let mut photos = paths
+        .iter()
+        .filter(|path| _is_picture(path))
+        .map(|path| (path, extract_content_identifier(path)))
+        .collect::<Vec<(&PathBuf, Option<String>)>>();
+
+    let mut pairs = vec![];
+    let mut unmatched_videos = vec![];
+
+    for (video, video_identifier) in videos {
+        match photos.iter().position(|(_, photo_identifier)| {
+            video_identifier.is_some() && photo_identifier == &video_identifier
+        }) {
+            Some(index) => {
+                let (photo, _) = photos.remove(index);
+                pairs.push(LivePhotoPair {
+                    photo: photo.clone(),
+                    video: video.clone(),
+                    content_identifier: video_identifier,
+                });
+            }
+            None => unmatched_videos.push((video, video_identifier)),
+        }
+    }
+
+    for (video, video_identifier) in unmatched_videos {
+        let same_name = |photo: &Path| {
+            photo.parent() == video.parent()
+                && photo.file_stem().zip(video.file_stem()).is_some_and(
+                    |(photo_stem, video_stem)| photo_stem.eq_ignore_ascii_case(video_stem),
+                )
+        };
+
+        if let Some(index) = photos.iter().position(|(photo, photo_identifier)| {
+            same_name(photo)
+                && (photo_identifier.is_none()
+                    || video_identifier.is_none()
+                    || photo_identifier == &video_identifier)
+        }) {
+            let (photo, _) = photos.remove(index);
+            pairs.push(LivePhotoPair {
+                photo: photo.clone(),
+                video: video.clone(),
+                content_identifier: None,
+            });
+        }
+    }
+
+    pairs
+}
+
 /// Helper function to turn a filename into a SQL-safe string format.
-fn get_sql_safe_filename(file: &PathBuf) -> Result<String, Error> {
+fn get_sql_safe_filename(file: &Path) -> Result<String, Error> {
     Ok(file.to_str().unwrap().replace("\\", "/").to_string())
 }
 
-/// Take a given file and target date, and copy the file into the output folder with the new filename.
-fn copy_file_and_mark_as_processed(
-    source_file: &PathBuf,
-    output_date: &chrono::NaiveDateTime,
+/// Calculate a BLAKE3 checksum of a file's contents, returned as a hex string.
+fn get_file_checksum(file: &Path) -> Result<String, Error> {
+    Ok(db::file_checksum(file)?)
+}
+
+/// Check whether a file with the given content checksum has already been processed, regardless of where
+/// it was originally found.
+fn has_checksum_been_processed(db_connection: &Connection, checksum: &str) -> Result<bool, Error> {
+    let mut check_statement = db_connection.prepare("SELECT * FROM files WHERE checksum = ?")?;
+
+    Ok(check_statement.exists(rusqlite::params![checksum])?)
+}
+
+/// Transfer a file to its destination using the given mode, returning the mode that was actually used, as
+/// reflinks and moves may fall back to copying.
+fn transfer_file(
+    source_file: &Path,
+    destination: &Path,
+    transfer_mode: TransferMode,
+) -> Result<TransferMode, Error> {
+    match transfer_mode {
+        TransferMode::Copy => {
+            fs::copy(source_file, destination)?;
+        }
+        TransferMode::Move => {
+            // Renames can't cross file systems, so fall back to copying and removing the original
+            if fs::rename(source_file, destination).is_err() {
+                fs::copy(source_file, destination)?;
+                fs::remove_file(source_file)?;
+            }
+        }
+        TransferMode::Hardlink => {
+            fs::hard_link(source_file, destination)?;
+        }
+        TransferMode::Symlink => {
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(source_file, destination)?;
+            #[cfg(windows)]
+            std::os::windows::fs::symlink_file(source_file, destination)?;
+        }
+        TransferMode::Reflink => {
+            if reflink_copy::reflink_or_copy(source_file, destination)?.is_some() {
+                return Ok(TransferMode::Copy);
+            }
+        }
+    }
+
+    Ok(transfer_mode)
+}
+
+/// The camera a file was taken with, as recorded in its EXIF data.
+#[derive(Clone, Default)]
+struct CameraDetails {
+    make: Option<String>,
+    model: Option<String>,
+    serial: Option<String>,
+}
+
+/// A date for a file that was found but not used, recorded in the DB as JSON.
+#[derive(Serialize, PartialEq)]
+struct DateCandidate {
+    source: DateSource,
+    date: String,
+    /// How far this is from the chosen date
+    #[serde(skip)]
+    difference: chrono::Duration,
+}
+
+/// Everything learned about a single file while analysing its group. Analysis only reads from source files,
+/// so is safe to run in parallel.
+struct AnalysedFile {
+    path: PathBuf,
+    date: CaptureTime,
+    date_source: DateSource,
+    /// Dates from other sources which disagree with the chosen one
+    date_candidates: Vec<DateCandidate>,
+    checksum: String,
+    size: u64,
+    /// Modified time of the source, in seconds since the Unix epoch
+    modified: Option<i64>,
+    camera: CameraDetails,
+    /// For sidecars, the file they belong to, from which everything but the checksum, size and modified time
+    /// is taken
+    parent: Option<PathBuf>,
+    /// For motion photos, where the embedded video is, if it's to be extracted
+    embedded_video: Option<EmbeddedVideo>,
+    /// For Live Photos, the other half of the pair
+    live_photo: Option<PathBuf>,
+}
+
+/// A file which has been allocated an output path and is waiting to be transferred.
+struct PlannedTransfer {
+    file: AnalysedFile,
+    destination: PathBuf,
+}
+
+/// The result of transferring a file, including anything else written out for it.
+struct CompletedTransfer {
+    transfer_mode: TransferMode,
+    /// Checksum of the output, if it differs from the source
+    destination_checksum: Option<String>,
+    /// The video extracted from a motion photo, if any
+    extracted_video: Option<PathBuf>,
+}
+
+/// The path a video extracted from a motion photo is written to, next to its still and named to match it.
+fn motion_photo_video_path(destination: &Path) -> PathBuf {
+    destination.with_extension("mp4")
+}
+
+/// Transfer an analysed file to its allocated destination, first writing out the video from a motion photo if
+/// configured to. Stripped stills are always written as new files, so the original is left in place even when
+/// moving, as it's the only complete copy.
+fn transfer_planned_file(
+    planned_transfer: &PlannedTransfer,
     renamer_config: &RenamerConfig,
-    renamer_args: &RenamerArgs,
-    db_connection: &Connection,
-) -> Result<(), Error> {
-    let mut insert_statement = db_connection.prepare("INSERT INTO files VALUES (?, ?)")?;
+) -> Result<CompletedTransfer, Error> {
+    let source_file = &planned_transfer.file.path;
+    let destination = &planned_transfer.destination;
+
+    // Create the output directory if not present
+    fs::create_dir_all(destination.parent().unwrap())?;
+
+    let embedded_video = match &planned_transfer.file.embedded_video {
+        None => {
+            return Ok(CompletedTransfer {
+                transfer_mode: transfer_file(
+                    source_file,
+                    destination,
+                    renamer_config.transfer_mode,
+                )?,
+                destination_checksum: None,
+                extracted_video: None,
+            })
+        }
+        Some(embedded_video) => embedded_video,
+    };
+
+    let video_destination = motion_photo_video_path(destination);
+    motion_photo::extract_video(source_file, embedded_video, &video_destination)?;
+
+    let result = match renamer_config.motion_photos {
+        MotionPhotoMode::Split => {
+            motion_photo::write_still(source_file, embedded_video, destination)
+                .and_then(|_| get_file_checksum(destination))
+                .map(|checksum| (TransferMode::Copy, Some(checksum)))
+        }
+        _ => transfer_file(source_file, destination, renamer_config.transfer_mode)
+            .map(|transfer_mode| (transfer_mode, None)),
+    };
+
+    match result {
+        Ok((transfer_mode, destination_checksum)) => Ok(CompletedTransfer {
+            transfer_mode,
+            destination_checksum,
+            extracted_video: Some(video_destination),
+        }),
+        Err(e) => {
+            // Don't leave the video behind without its still
+            let _ = fs::remove_file(&video_destination);
+            Err(e)
+        }
+    }
+}
+
+/// Render the output path for an analysed file with the given collision counter. The camera details are passed
+/// separately so that every file in a group can be named after the same camera.
+fn render_output_path(
+    analysed_file: &AnalysedFile,
+    camera: &CameraDetails,
+    counter: u32,
+    renamer_config: &RenamerConfig,
+) -> PathBuf {
+    let source_file = &analysed_file.path;
 
     let has_mp_tag = source_file
         .file_name()
@@ -209,143 +654,399 @@ fn copy_file_and_mark_as_processed(
         .to_lowercase()
         .split('.')
         .collect::<Vec<&str>>()
-        .contains(&&"mp");
+        .contains(&"mp");
     let is_mvimg = source_file
         .file_stem()
         .unwrap()
         .to_str()
         .unwrap()
         .to_lowercase()
-        .starts_with(&"mvimg");
+        .starts_with("mvimg");
+
+    let source_stem = source_file.file_stem().unwrap().to_str().unwrap();
+    let source_extension = source_file.extension().unwrap().to_str().unwrap();
+
+    let template_values = TemplateValues {
+        date: &analysed_file.date,
+        counter,
+        stem: source_stem,
+        make: camera.make.as_deref(),
+        model: camera.model.as_deref(),
+        extension: source_extension,
+        // Stripped stills are no longer motion photos, so shouldn't be marked as one
+        motion_photo: (has_mp_tag || is_mvimg)
+            && !(renamer_config.motion_photos == MotionPhotoMode::Split
+                && analysed_file.embedded_video.is_some()),
+    };
 
-    for counter in 0..99 {
-        let mut new_path = if _is_raw(source_file) {
-            PathBuf::from(&renamer_config.raw_output_path)
-        } else {
-            PathBuf::from(&renamer_config.output_path)
-        };
+    let mut output_directory = if _is_raw(source_file) {
+        PathBuf::from(&renamer_config.raw_output_path)
+    } else {
+        PathBuf::from(&renamer_config.output_path)
+    };
 
-        // Create the output directory if not present
-        if !new_path.exists() {
-            fs::create_dir_all(&new_path)?;
-        }
+    output_directory.push(renamer_config.directory_template.render(&template_values));
 
-        let mut filename_components: Vec<String> = vec![];
+    let mut new_filename = renamer_config.filename_template.render(&template_values);
 
-        // New filename starts with the datetime
-        filename_components.push(output_date.format("%Y%m%d_%H%M%S").to_string());
+    if renamer_config.lowercase_filenames {
+        new_filename = new_filename.to_lowercase();
+    }
 
-        // If not the first attempt, we must have found a duplicate filename, so bump up the counter to try again with a different name
-        if counter != 0 {
-            filename_components.push(counter.to_string());
-        }
+    output_directory.join(new_filename)
+}
 
-        if has_mp_tag || is_mvimg {
-            filename_components.push("mp".to_string());
+/// Whether a sidecar is named after its primary's full name, such as IMG_0001.CR2.xmp, rather than just its stem.
+fn is_named_after_full_name(sidecar: &Path, primary: &Path) -> bool {
+    match (sidecar.file_stem(), primary.file_name()) {
+        (Some(sidecar_stem), Some(primary_name)) => sidecar_stem.eq_ignore_ascii_case(primary_name),
+        _ => false,
+    }
+}
+
+/// Find the file a sidecar belongs to among the files in its group. This must be in the same directory and
+/// either be named after the sidecar's stem, as with IMG_0001.CR2.xmp, or share its stem. Where several files
+/// share the stem, RAWs are preferred as that's what editors write sidecars for, apart from THM thumbnails
+/// which belong to movies.
+fn find_sidecar_primary<'a>(sidecar: &Path, paths: &'a [PathBuf]) -> Option<&'a PathBuf> {
+    let candidates = paths
+        .iter()
+        .filter(|path| file_in_scope(path) && path.parent() == sidecar.parent())
+        .collect::<Vec<&PathBuf>>();
+
+    if let Some(primary) = candidates
+        .iter()
+        .find(|path| is_named_after_full_name(sidecar, path))
+    {
+        return Some(primary);
+    }
+
+    let is_thumbnail = sidecar
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("thm"));
+
+    let preference = |path: &Path| match (is_thumbnail, _is_raw(path), _is_movie(path)) {
+        (true, _, true) | (false, true, _) => 0,
+        (_, _, false) => 1,
+        _ => 2,
+    };
+
+    candidates
+        .into_iter()
+        .filter(|path| {
+            path.file_stem()
+                .zip(sidecar.file_stem())
+                .is_some_and(|(primary_stem, sidecar_stem)| {
+                    primary_stem.eq_ignore_ascii_case(sidecar_stem)
+                })
+        })
+        .min_by_key(|path| (preference(path), path.to_path_buf()))
+}
+
+/// The output path for a sidecar, next to its primary's output path and named to match it.
+fn sidecar_output_path(
+    sidecar: &Path,
+    primary: &Path,
+    primary_destination: &Path,
+    renamer_config: &RenamerConfig,
+) -> PathBuf {
+    let mut extension = sidecar.extension().unwrap().to_str().unwrap().to_string();
+
+    if renamer_config.lowercase_filenames {
+        extension = extension.to_lowercase();
+    }
+
+    let mut new_filename = match is_named_after_full_name(sidecar, primary) {
+        true => primary_destination.file_name(),
+        false => primary_destination.file_stem(),
+    }
+    .unwrap()
+    .to_os_string();
+
+    new_filename.push(".");
+    new_filename.push(extension);
+
+    primary_destination.with_file_name(new_filename)
+}
+
+/// Whether two files have the same extension, ignoring case.
+fn same_extension(a: &Path, b: &Path) -> bool {
+    let extension = |path: &Path| path.extension().map(|ext| ext.to_ascii_lowercase());
+
+    extension(a) == extension(b)
+}
+
+/// Determine the output paths for a set of files from the same group that share a date, such as a RAW and its
+/// JPEG, avoiding both existing files and any paths already allocated to other files in this run. A single
+/// collision counter is picked for the whole set, so that they all end up with the same name apart from the
+/// extension, even though RAWs and other files are written to different directories. Sidecars in the set are
+/// placed next to their primary. Returns None if no free names could be found.
+fn allocate_output_paths(
+    analysed_files: &[&AnalysedFile],
+    renamer_config: &RenamerConfig,
+    reserved_paths: &mut HashSet<PathBuf>,
+) -> Option<Vec<PathBuf>> {
+    // RAWs are often missing the camera details their JPEG has, so name everything after the first file to
+    // have them
+    let default_camera = CameraDetails::default();
+    let camera = analysed_files
+        .iter()
+        .map(|analysed_file| &analysed_file.camera)
+        .find(|camera| camera.make.is_some() || camera.model.is_some())
+        .unwrap_or(&default_camera);
+
+    // There's no cap on the counter, as names from dates without a time, such as WhatsApp's, leave every file
+    // from the same day competing for one name
+    for counter in 0..=u32::MAX {
+        // If not the first attempt, we must have found a duplicate filename, so the counter will be bumped up to
+        // try again with a different name
+        let mut new_paths = analysed_files
+            .iter()
+            .map(|analysed_file| render_output_path(analysed_file, camera, counter, renamer_config))
+            .collect::<Vec<PathBuf>>();
+
+        for (index, analysed_file) in analysed_files.iter().enumerate() {
+            if let Some(parent_index) = analysed_file.parent.as_ref().and_then(|parent| {
+                analysed_files
+                    .iter()
+                    .position(|primary_file| &primary_file.path == parent)
+            }) {
+                new_paths[index] = sidecar_output_path(
+                    &analysed_file.path,
+                    &analysed_files[parent_index].path,
+                    &new_paths[parent_index],
+                    renamer_config,
+                );
+            }
         }
 
-        filename_components.push(
-            source_file
-                .extension()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_string(),
-        );
+        // Videos extracted from motion photos need a free name too
+        let video_paths = analysed_files
+            .iter()
+            .zip(&new_paths)
+            .filter(|(analysed_file, _)| analysed_file.embedded_video.is_some())
+            .map(|(_, new_path)| motion_photo_video_path(new_path))
+            .collect::<Vec<PathBuf>>();
 
-        new_path.push(filename_components.join("."));
+        let mut unique_paths = HashSet::new();
 
-        if new_path.exists() {
+        if new_paths.iter().chain(&video_paths).any(|new_path| {
+            new_path.exists() || reserved_paths.contains(new_path) || !unique_paths.insert(new_path)
+        }) {
             // We found a matching entry, try again, which will bump up the counter
             continue;
         }
 
-        let final_path = new_path.to_str().unwrap().to_lowercase();
+        reserved_paths.extend(new_paths.iter().chain(&video_paths).cloned());
 
-        if renamer_args.test_mode {
-            info!(
-                "Would have copied {} to {}",
-                source_file.to_str().unwrap(),
-                final_path
-            );
-            return Ok(());
-        }
+        return Some(new_paths);
+    }
+
+    None
+}
+
+/// Read the EXIF data from a file, using the dedicated parsers for RAW and HEIF files as their containers
+/// vary.
+fn read_exif(filename: &Path) -> Result<Exif, Error> {
+    if _is_raw(filename) {
+        return raw::read_exif(filename);
+    }
 
-        fs::copy(source_file, final_path)?;
+    if _is_heif(filename) {
+        return heif::read_exif(filename);
+    }
 
-        let sql_safe_filename = get_sql_safe_filename(source_file)?;
+    let input_file = File::open(filename)?;
 
-        insert_statement.insert(rusqlite::params![&sql_safe_filename, &sql_safe_filename])?;
+    Ok(Reader::new().read_from_container(&mut std::io::BufReader::new(&input_file))?)
+}
 
-        break;
+/// Read a text field from EXIF data, ignoring it if empty.
+fn get_exif_string(exif_data: &Exif, tag: Tag) -> Option<String> {
+    match &exif_data.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(values) => values
+            .first()
+            .map(|value| String::from_utf8_lossy(value).trim().to_string())
+            .filter(|value| !value.is_empty()),
+        _ => None,
     }
+}
 
-    Ok(())
+/// Extract, where possible, the camera make, model and serial number from a file's EXIF data.
+fn extract_camera_details_from_exif(exif_data: &Exif) -> CameraDetails {
+    CameraDetails {
+        make: get_exif_string(exif_data, Tag::Make),
+        model: get_exif_string(exif_data, Tag::Model),
+        serial: get_exif_string(exif_data, Tag::BodySerialNumber),
+    }
 }
 
-/// Extract, where possible, a datetime from a file's EXIF data.
-fn extract_timestamp_from_exif(filename: &PathBuf) -> Result<chrono::NaiveDateTime, Error> {
-    let input_file = File::open(&filename)?;
+/// Read a date and time field from EXIF data, adding the sub-seconds and UTC offset from the given fields if
+/// the camera recorded them.
+fn get_exif_datetime(
+    exif_data: &Exif,
+    datetime_tag: Tag,
+    subsec_tag: Tag,
+    offset_tag: Tag,
+) -> Option<CaptureTime> {
+    let disp = exif_data
+        .get_field(datetime_tag, In::PRIMARY)?
+        .value
+        .display_as(datetime_tag)
+        .to_string();
+
+    let mut photo_datetime =
+        chrono::NaiveDateTime::parse_from_str(&disp, "%Y-%m-%d %H:%M:%S").ok()?;
+
+    // Sub-seconds are stored as the digits after the decimal point, so "5" is half a second
+    if let Some(subsec) = get_exif_string(exif_data, subsec_tag) {
+        let digits = subsec
+            .chars()
+            .take_while(|c| c.is_ascii_digit())
+            .take(9)
+            .collect::<String>();
+
+        if let Ok(nanoseconds) = format!("{:0<9}", digits).parse::<u32>() {
+            photo_datetime = photo_datetime
+                .with_nanosecond(nanoseconds)
+                .unwrap_or(photo_datetime);
+        }
+    }
 
-    // First start by trying to get hold of the exif data
-    let exif_data = Reader::new().read_from_container(&mut std::io::BufReader::new(&input_file))?;
+    Some(
+        match get_exif_string(exif_data, offset_tag)
+            .and_then(|offset| timestamp::parse_offset(&offset))
+        {
+            Some(offset) => CaptureTime::with_offset(photo_datetime, offset),
+            None => CaptureTime::naive(photo_datetime),
+        },
+    )
+}
 
-    let photo_datetime_field = match exif_data.get_field(Tag::DateTimeOriginal, In::PRIMARY) {
-        None => {
-            return Err(anyhow!(
-                "DateTimeOriginal field not available for {:?}",
-                filename
-            ));
+/// Read the GPS date and time from EXIF data. These are in UTC, so are converted to the offset the photo
+/// was taken at if recorded, or to local time otherwise.
+fn get_exif_gps_datetime(exif_data: &Exif) -> Option<CaptureTime> {
+    let date = chrono::NaiveDate::parse_from_str(
+        &get_exif_string(exif_data, Tag::GPSDateStamp)?,
+        "%Y:%m:%d",
+    )
+    .ok()?;
+
+    let time = match &exif_data.get_field(Tag::GPSTimeStamp, In::PRIMARY)?.value {
+        Value::Rational(values) if values.len() == 3 => {
+            let seconds =
+                values[0].to_f64() * 3600.0 + values[1].to_f64() * 60.0 + values[2].to_f64();
+
+            if !seconds.is_finite() || !(0.0..86400.0).contains(&seconds) {
+                return None;
+            }
+
+            chrono::NaiveTime::from_num_seconds_from_midnight_opt(
+                seconds as u32,
+                ((seconds.fract() * 1e9) as u32).min(999_999_999),
+            )?
         }
-        Some(field) => field,
+        _ => return None,
     };
 
-    let disp = photo_datetime_field
-        .value
-        .display_as(Tag::DateTimeOriginal)
-        .to_string();
+    let utc_datetime = date.and_time(time);
+
+    let offset = [Tag::OffsetTimeOriginal, Tag::OffsetTime]
+        .into_iter()
+        .find_map(|tag| timestamp::parse_offset(&get_exif_string(exif_data, tag)?));
 
-    Ok(chrono::NaiveDateTime::parse_from_str(
-        &disp,
-        "%Y-%m-%d %H:%M:%S",
-    )?)
+    Some(match offset {
+        Some(offset) => CaptureTime::with_offset(utc_datetime + offset, offset),
+        None => CaptureTime::from_utc(utc_datetime),
+    })
 }
 
-/// Extract, where possible, a datetime from a file's name.
-fn extract_datetime_from_filename(file: &PathBuf) -> Option<chrono::NaiveDateTime> {
-    let filename = file.file_stem()?.to_str()?;
+/// Extract, where possible, a datetime from a file's EXIF data, using the first of the given fields that
+/// holds a valid date.
+fn extract_timestamp_from_exif(
+    filename: &Path,
+    exif_data: &Exif,
+    exif_date_fields: &[ExifDateField],
+) -> Result<CaptureTime, Error> {
+    exif_date_fields
+        .iter()
+        .find_map(|exif_date_field| match exif_date_field {
+            ExifDateField::DateTimeOriginal => get_exif_datetime(
+                exif_data,
+                Tag::DateTimeOriginal,
+                Tag::SubSecTimeOriginal,
+                Tag::OffsetTimeOriginal,
+            ),
+            ExifDateField::DateTimeDigitized => get_exif_datetime(
+                exif_data,
+                Tag::DateTimeDigitized,
+                Tag::SubSecTimeDigitized,
+                Tag::OffsetTimeDigitized,
+            ),
+            ExifDateField::DateTime => {
+                get_exif_datetime(exif_data, Tag::DateTime, Tag::SubSecTime, Tag::OffsetTime)
+            }
+            ExifDateField::Gps => get_exif_gps_datetime(exif_data),
+        })
+        .ok_or_else(|| anyhow!("No valid EXIF date available for {:?}", filename))
+}
 
-    let filename_regex = Regex::new(r"(\d{8})[-_]?(\d{6})").ok()?;
+/// Extract, where possible, a datetime from a file's embedded metadata: EXIF for pictures, and the
+/// QuickTime/MP4 atoms for movies.
+fn extract_timestamp_from_metadata(
+    filename: &Path,
+    renamer_config: &RenamerConfig,
+) -> Result<CaptureTime, Error> {
+    if _is_movie(filename) {
+        return quicktime::extract_creation_time(filename);
+    }
 
-    if let Some(captures) = filename_regex.captures(&filename) {
-        if captures.len() == 1 {
-            let first_capture = captures.get(1)?;
-            let second_capture = captures.get(2)?;
+    extract_timestamp_from_exif(
+        filename,
+        &read_exif(filename)?,
+        &renamer_config.exif_date_fields,
+    )
+}
 
-            if let Ok(file_date) = chrono::NaiveDateTime::parse_from_str(
-                format!("{}{}", first_capture.as_str(), second_capture.as_str()).as_str(),
-                "%Y%m%d%H%M%S",
-            ) {
-                return Some(file_date);
-            };
-        };
-    };
+/// Extract, where possible, a datetime from a file's name, using the first of the configured patterns that
+/// matches. The pattern used is returned too.
+fn extract_datetime_from_filename<'a>(
+    file: &Path,
+    renamer_config: &'a RenamerConfig,
+) -> Option<(CaptureTime, &'a FilenamePattern)> {
+    let filename = file.file_stem()?.to_str()?;
 
-    None
+    filename::extract_date(filename, &renamer_config.filename_patterns)
+}
+
+/// Extract, where possible, a datetime from the names of the directories holding a file, using the first of
+/// the configured patterns that matches. The pattern used and the matching directory name are returned too.
+fn extract_datetime_from_directory<'a>(
+    file: &'a Path,
+    renamer_config: &'a RenamerConfig,
+) -> Option<(CaptureTime, &'a DirectoryPattern, &'a str)> {
+    directory::extract_date(
+        file,
+        find_root_path(file, renamer_config).as_deref(),
+        &renamer_config.directory_patterns,
+        &renamer_config.directory_date_defaults,
+    )
 }
 
 /// Extract, where possible, a datetime from a file's metadata, specifically, the file's modified time.
-fn extract_datetime_from_file_metadata(file: &PathBuf) -> Option<chrono::NaiveDateTime> {
-    if let Some(file_metadata) = fs::metadata(file).ok() {
+fn extract_datetime_from_file_metadata(file: &Path) -> Option<CaptureTime> {
+    if let Ok(file_metadata) = fs::metadata(file) {
         if let Ok(file_metadata_modified) = file_metadata.modified() {
-            return Some(chrono::DateTime::<Local>::from(file_metadata_modified).naive_local());
+            return Some(CaptureTime::from_utc(
+                chrono::DateTime::<chrono::Utc>::from(file_metadata_modified).naive_utc(),
+            ));
         }
     }
 
     None
 }
 
-fn has_file_been_processed(db_connection: &Connection, path: &PathBuf) -> bool {
+fn has_file_been_processed(db_connection: &Connection, path: &Path) -> bool {
     let mut check_statement = db_connection
         .prepare("SELECT * FROM files WHERE filename = ?")
         .unwrap();
@@ -355,129 +1056,808 @@ fn has_file_been_processed(db_connection: &Connection, path: &PathBuf) -> bool {
         .unwrap()
 }
 
-/// Process all filenames, copying them if not already copied and if it is possible to determine a valid
-/// date to use for output filename formatting.
-fn process_files(
-    db_connection: &Connection,
-    filenames: &HashMap<String, Vec<PathBuf>>,
+/// Find the files which still need processing, along with any sidecars, which are processed alongside them.
+/// Without a DB, nothing has been processed yet.
+fn find_pending_paths(
+    db_connection: Option<&Connection>,
+    filenames: &HashMap<GroupKey, Vec<PathBuf>>,
+) -> HashSet<PathBuf> {
+    filenames
+        .values()
+        .flatten()
+        .filter(|path| {
+            (file_in_scope(path) || _is_sidecar(path))
+                && !db_connection
+                    .is_some_and(|db_connection| has_file_been_processed(db_connection, path))
+        })
+        .cloned()
+        .collect()
+}
+
+/// Read everything needed to rename the pending files of a single group: a date for each file, its
+/// checksum and, if the templates or clock corrections need them, camera details. Files for which no date can
+/// be determined are returned as errors.
+fn analyse_group(
+    paths: &[PathBuf],
+    pending_paths: &[&PathBuf],
     renamer_config: &RenamerConfig,
-    renamer_args: &RenamerArgs,
-) -> Result<(), Error> {
-    info!("Beginning media rename operation...");
+) -> Vec<Result<AnalysedFile, String>> {
+    let live_photo_pairs = find_live_photo_pairs(paths);
+
+    let is_live_photo_video = |path: &Path| live_photo_pairs.iter().any(|pair| pair.video == path);
+
+    // Parsing EXIF can mean reading a fair amount of a file, so do it just once for each picture, and share the
+    // result between finding dates and camera details
+    let exif_data = paths
+        .iter()
+        .filter(|path| file_in_scope(path) && !_is_movie(path))
+        .map(|path| (path.as_path(), read_exif(path).ok()))
+        .collect::<HashMap<&Path, Option<Exif>>>();
+
+    // Try to determine a unique datetime for the files with the same prefix. We may be mixing
+    // raws with jpgs, and getting raw file info is harder than it seems apparently, so if we can get a single unique
+    // datetime from one or more jpgs, we can assume they apply to any raws too. Live Photo videos can start a
+    // fraction of a second away from their photo, which would stop the group agreeing, so they're only used if
+    // nothing else in the group has a date.
+    let metadata_dates = |include_live_photo_videos: bool| {
+        paths
+            .iter()
+            .filter(|path| file_in_scope(path))
+            .filter(|path| include_live_photo_videos || !is_live_photo_video(path))
+            .flat_map(|path| match exif_data.get(path.as_path()) {
+                Some(exif_data) => exif_data.as_ref().and_then(|exif_data| {
+                    extract_timestamp_from_exif(path, exif_data, &renamer_config.exif_date_fields)
+                        .ok()
+                }),
+                None => extract_timestamp_from_metadata(path, renamer_config).ok(),
+            })
+            .collect::<Vec<CaptureTime>>()
+    };
 
-    let mut successful_file_copy_count = 0;
-    let mut processed_file_count: u64 = 0;
-    let pb = ProgressBar::new(filenames.len() as u64);
+    let potential_dates = match metadata_dates(false) {
+        dates if dates.is_empty() => metadata_dates(true),
+        dates => dates,
+    };
 
-    // Shamelessly copied from https://github.com/console-rs/indicatif/blob/HEAD/examples/download.rs
-    pb.set_style(
-        ProgressStyle::with_template(
-            "{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len} ({eta})",
-        )?
-        .with_key("eta", |state: &ProgressState, w: &mut dyn FmtWrite| {
-            write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap()
-        })
-        .progress_chars("#>-"),
-    );
+    // Cameras with reset clocks write garbage dates, so ignore any outside the plausible range. RAWs often lack
+    // the sub-seconds or offset that their JPEG has, so treat dates within the same second as agreeing, and use
+    // the most precise of them.
+    let plausible_dates = potential_dates
+        .iter()
+        .filter(|date| renamer_config.is_plausible(date))
+        .collect::<Vec<&CaptureTime>>();
+
+    let unique_date = match plausible_dates.first() {
+        Some(first_date)
+            if plausible_dates
+                .iter()
+                .all(|date| date.same_second(first_date)) =>
+        {
+            plausible_dates
+                .iter()
+                .copied()
+                .max_by_key(|date| (date.offset.is_some(), date.local.nanosecond() != 0))
+                .copied()
+        }
+        _ => None,
+    };
 
-    let mut errors: Vec<String> = vec![];
+    let needs_camera_details = renamer_config.filename_template.uses_camera_details()
+        || renamer_config.directory_template.uses_camera_details()
+        || !renamer_config.camera_offsets.is_empty();
 
-    for (_, paths) in filenames {
-        // Before we begin, let's update the status bar
-        processed_file_count += 1;
-        pb.set_position(processed_file_count);
+    let mut results = vec![];
 
-        // Firstly, check whether all paths in the paths vec have been processed already. If so, we can
-        // move on now.
-        if paths
+    let (sidecar_paths, primary_paths): (Vec<&PathBuf>, Vec<&PathBuf>) =
+        pending_paths.iter().partition(|path| _is_sidecar(path));
+
+    // RAWs are often missing the camera details their JPEG has, so the whole group is treated as coming from
+    // the first file to have them, in the same order they're picked when naming. Files already processed are
+    // only used if none of the pending ones have any, so that a RAW arriving later still matches its JPEG.
+    let camera = match needs_camera_details {
+        false => CameraDetails::default(),
+        true => primary_paths
             .iter()
-            .map(|path| has_file_been_processed(db_connection, path))
-            .all(|result| result == true)
-        {
-            continue;
-        }
+            .copied()
+            .chain(paths.iter().filter(|path| file_in_scope(path)))
+            .filter_map(|path| exif_data.get(path.as_path())?.as_ref())
+            .map(extract_camera_details_from_exif)
+            .find(|camera| camera.make.is_some() || camera.model.is_some())
+            .unwrap_or_default(),
+    };
 
-        // Try to determine a unique datetime for the files with the same prefix. We may be mixing
-        // raws with jpgs, and getting raw file info is harder than it seems apparently, so if we can get a single unique
-        // datetime from one or more jpgs, we can assume they apply to any raws too.
-        let potential_dates = paths
-            .into_iter()
-            .map(|path| extract_timestamp_from_exif(path))
-            .filter(|result| result.is_ok())
-            .map(|result| result.unwrap())
-            .collect::<HashSet<chrono::NaiveDateTime>>();
+    // The dates found for each file from each of its configured sources, in order, along with the one it would
+    // be given on its own
+    let mut dated_paths = vec![];
 
-        for path in paths {
-            if !file_in_scope(path) {
-                continue;
-            }
+    for path in primary_paths {
+        let filename_date =
+            extract_datetime_from_filename(path, renamer_config).map(|(date, _)| date);
+        let directory_date =
+            extract_datetime_from_directory(path, renamer_config).map(|(date, _, _)| date);
+        let mtime_date = extract_datetime_from_file_metadata(path);
 
-            // We have a file we can investigate. Check whether we've seen it before. If so, we'll skip
-            if has_file_been_processed(db_connection, path) {
-                continue;
-            }
+        let date_sources = renamer_config.date_sources_for(path);
 
-            if potential_dates.len() == 1 {
-                copy_file_and_mark_as_processed(
-                    path,
-                    &potential_dates.iter().next().unwrap(),
-                    renamer_config,
-                    renamer_args,
-                    db_connection,
-                )?;
+        let source_dates = date_sources
+            .iter()
+            .map(|date_source| {
+                let dates = match date_source {
+                    DateSource::Metadata => potential_dates.clone(),
+                    DateSource::Filename => filename_date.into_iter().collect(),
+                    DateSource::Directory => directory_date.into_iter().collect(),
+                    DateSource::Mtime => mtime_date.into_iter().collect(),
+                };
+                (*date_source, dates)
+            })
+            .collect::<Vec<(DateSource, Vec<CaptureTime>)>>();
+
+        // Use the first of the configured sources that has a plausible date. By default that's the date shared
+        // by the group, then if there wasn't one, the filename, the directories the file is in and finally the
+        // file's modified time.
+        let date = date_sources.iter().find_map(|date_source| {
+            match date_source {
+                DateSource::Metadata => unique_date,
+                DateSource::Filename => filename_date,
+                DateSource::Directory => directory_date,
+                DateSource::Mtime => mtime_date,
+            }
+            .filter(|date| renamer_config.is_plausible(date))
+            .map(|date| (*date_source, date))
+        });
 
-                successful_file_copy_count += 1;
+        dated_paths.push((path, date, source_dates));
+    }
 
+    // Every file in the group shares one date, so that they're all given the same name apart from the
+    // extension, even where they'd be dated differently on their own. It's taken from the file dated from the
+    // most reliable source, preferring the photo of a Live Photo so that its video follows it even if a
+    // different date source is configured for movies.
+    let group_date = dated_paths
+        .iter()
+        .filter_map(|(path, date, _)| Some((*path, (*date)?)))
+        .min_by_key(|(path, (date_source, _))| (is_live_photo_video(path), *date_source, *path))
+        .map(|(_, date)| date);
+
+    for (path, _, source_dates) in dated_paths {
+        let (date_source, mut date) = match group_date {
+            // At this stage, you're just out of luck
+            None => {
+                let rejected_dates = source_dates
+                    .iter()
+                    .flat_map(|(date_source, dates)| {
+                        dates
+                            .iter()
+                            .filter(|date| !renamer_config.is_plausible(date))
+                            .map(move |date| format!("{} from {}", date, date_source))
+                    })
+                    .collect::<Vec<String>>();
+
+                results.push(Err(match rejected_dates.is_empty() {
+                    true => format!(
+                        "Unable to determine valid datetime for {}",
+                        path.to_str().unwrap()
+                    ),
+                    false => format!(
+                        "Unable to determine a plausible datetime for {}, rejected {}",
+                        path.to_str().unwrap(),
+                        rejected_dates.join(", ")
+                    ),
+                }));
                 continue;
             }
+            Some(group_date) => group_date,
+        };
 
-            // No unique data for the files could be determined, so we'll need to get a bit funky here. Let's start
-            // by trying to parse the date and time of the file from the filename
-            if let Some(filename_datetime) = extract_datetime_from_filename(path) {
-                copy_file_and_mark_as_processed(
-                    &path,
-                    &filename_datetime,
-                    renamer_config,
-                    renamer_args,
-                    db_connection,
-                )?;
+        // Keep hold of anything from the configured sources that disagrees with the shared date, so that it
+        // can be audited later
+        let mut date_candidates: Vec<DateCandidate> = vec![];
 
-                successful_file_copy_count += 1;
+        for (candidate_source, candidate_dates) in source_dates {
+            for candidate_date in candidate_dates {
+                let candidate = DateCandidate {
+                    source: candidate_source,
+                    date: candidate_date.to_string(),
+                    difference: candidate_date.difference(&date),
+                };
 
-                continue;
+                if !candidate_date.same_second(&date) && !date_candidates.contains(&candidate) {
+                    date_candidates.push(candidate);
+                }
             }
+        }
 
-            // Apparently, that didn't work either. Next step - file timestamps time.
-            if let Some(filename_datetime) = extract_datetime_from_file_metadata(path) {
-                copy_file_and_mark_as_processed(
-                    &path,
-                    &filename_datetime,
-                    renamer_config,
-                    renamer_args,
-                    db_connection,
-                )?;
+        // Correct for any camera with a wrong clock before converting time zones, as the correction applies
+        // to the time shown on the camera
+        if let Some(clock_offset) = renamer_config.camera_offset(
+            camera.make.as_deref(),
+            camera.model.as_deref(),
+            camera.serial.as_deref(),
+        ) {
+            date = date.shifted(clock_offset.0);
+        }
 
-                successful_file_copy_count += 1;
+        if let Some(target_timezone) = &renamer_config.target_timezone {
+            date = date.normalise(target_timezone);
+        }
 
+        let checksum = match get_file_checksum(path) {
+            Err(e) => {
+                results.push(Err(format!(
+                    "Unable to calculate checksum for {}: {}",
+                    path.to_str().unwrap(),
+                    e
+                )));
                 continue;
             }
+            Ok(checksum) => checksum,
+        };
 
-            // At this stage, you're just out of luck
-            let filename_string = String::from(path.to_str().unwrap());
+        let (size, modified) = get_size_and_modified_time(path);
 
-            errors.push(format!(
-                "Unable to determine valid datetime for {}",
-                filename_string
-            ));
+        let embedded_video = match renamer_config.motion_photos {
+            MotionPhotoMode::Keep => None,
+            _ if !_is_picture(path) || _is_heif(path) => None,
+            _ => match motion_photo::find_embedded_video(path) {
+                Ok(embedded_video) => embedded_video,
+                Err(e) => {
+                    warn!("{}, so it will be transferred as it is", e);
+                    None
+                }
+            },
+        };
+
+        results.push(Ok(AnalysedFile {
+            path: (*path).clone(),
+            date,
+            date_source,
+            date_candidates,
+            checksum,
+            size,
+            modified,
+            camera: camera.clone(),
+            parent: None,
+            embedded_video,
+            live_photo: live_photo_pairs.iter().find_map(|pair| {
+                match (pair.photo == **path, pair.video == **path) {
+                    (true, _) => Some(pair.video.clone()),
+                    (_, true) => Some(pair.photo.clone()),
+                    _ => None,
+                }
+            }),
+        }));
+    }
+
+    // Sidecars follow their primary, so are only processed alongside it. Any whose primary has already been
+    // processed, or couldn't be dated, are left where they are.
+    let mut sidecar_results = vec![];
+
+    for sidecar_path in sidecar_paths {
+        let primary_file = find_sidecar_primary(sidecar_path, paths).and_then(|primary_path| {
+            results
+                .iter()
+                .flatten()
+                .find(|analysed_file| &analysed_file.path == primary_path)
+        });
+
+        let primary_file = match primary_file {
+            None => continue,
+            Some(primary_file) => primary_file,
+        };
+
+        let checksum = match get_file_checksum(sidecar_path) {
+            Err(e) => {
+                sidecar_results.push(Err(format!(
+                    "Unable to calculate checksum for {}: {}",
+                    sidecar_path.to_str().unwrap(),
+                    e
+                )));
+                continue;
+            }
+            Ok(checksum) => checksum,
+        };
+
+        let (size, modified) = get_size_and_modified_time(sidecar_path);
+
+        sidecar_results.push(Ok(AnalysedFile {
+            path: sidecar_path.clone(),
+            date: primary_file.date,
+            date_source: primary_file.date_source,
+            date_candidates: vec![],
+            checksum,
+            size,
+            modified,
+            camera: primary_file.camera.clone(),
+            parent: Some(primary_file.path.clone()),
+            embedded_video: None,
+            live_photo: None,
+        }));
+    }
+
+    results.extend(sidecar_results);
+
+    results
+}
+
+/// Split the analysed files from a group into the sets that are named together. Files in a group, such as a
+/// RAW and its JPEG, share a date so that they end up with the same name apart from the extension. Files with
+/// the same extension can't share a name, so those are kept apart and pick up their own collision counter.
+/// Sidecars always go with their primary.
+fn pair_group_files(analysed_files: Vec<AnalysedFile>) -> Vec<Vec<AnalysedFile>> {
+    let mut pairings: Vec<Vec<AnalysedFile>> = vec![];
+
+    for analysed_file in analysed_files {
+        match pairings
+            .iter_mut()
+            .find(|pairing| match &analysed_file.parent {
+                Some(parent) => pairing
+                    .iter()
+                    .any(|paired_file| &paired_file.path == parent),
+                None => !pairing
+                    .iter()
+                    .any(|paired_file| same_extension(&paired_file.path, &analysed_file.path)),
+            }) {
+            Some(pairing) => pairing.push(analysed_file),
+            None => pairings.push(vec![analysed_file]),
         }
     }
 
+    pairings
+}
+
+/// Read a file's size, and its modified time in seconds since the Unix epoch, where available.
+fn get_size_and_modified_time(path: &Path) -> (u64, Option<i64>) {
+    let source_metadata = fs::metadata(path).ok();
+    let size = source_metadata
+        .as_ref()
+        .map(|metadata| metadata.len())
+        .unwrap_or_default();
+    let modified = source_metadata
+        .and_then(|metadata| metadata.modified().ok())
+        .map(|modified| chrono::DateTime::<chrono::Utc>::from(modified).timestamp());
+
+    (size, modified)
+}
+
+/// Build a progress bar in the standard style for the given number of steps.
+fn get_progress_bar(length: u64) -> Result<ProgressBar, Error> {
+    let pb = ProgressBar::new(length);
+
+    // Shamelessly copied from https://github.com/console-rs/indicatif/blob/HEAD/examples/download.rs
+    pb.set_style(
+        ProgressStyle::with_template(
+            "{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len} ({eta})",
+        )?
+        .with_key("eta", |state: &ProgressState, w: &mut dyn FmtWrite| {
+            write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap()
+        })
+        .progress_chars("#>-"),
+    );
+
+    Ok(pb)
+}
+
+/// Add a file to the DB, along with how its date was determined. Duplicates have no transfer mode or
+/// destination.
+fn record_file(
+    db_connection: &Connection,
+    analysed_file: &AnalysedFile,
+    transfer_mode: Option<TransferMode>,
+    destination: Option<&str>,
+    destination_checksum: Option<&str>,
+    run_id: &str,
+) -> Result<(), Error> {
+    let date_candidates = match analysed_file.date_candidates.is_empty() {
+        true => None,
+        false => Some(serde_json::to_string(&analysed_file.date_candidates)?),
+    };
+
+    let parent = match &analysed_file.parent {
+        Some(parent) => Some(get_sql_safe_filename(parent)?),
+        None => None,
+    };
+
+    let live_photo = match &analysed_file.live_photo {
+        Some(live_photo) => Some(get_sql_safe_filename(live_photo)?),
+        None => None,
+    };
+
+    let mut insert_statement = db_connection.prepare_cached(
+        "INSERT INTO files (filename, checksum, transfer_mode, destination, run_id, size, mtime, date_source, \
+         date, date_candidates, parent, destination_checksum, live_photo) \
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    )?;
+
+    insert_statement.insert(rusqlite::params![
+        &get_sql_safe_filename(&analysed_file.path)?,
+        &analysed_file.checksum,
+        transfer_mode.map(|transfer_mode| transfer_mode.as_str()),
+        destination,
+        run_id,
+        analysed_file.size,
+        analysed_file.modified,
+        analysed_file.date_source.as_str(),
+        analysed_file.date.to_string(),
+        date_candidates,
+        parent,
+        destination_checksum,
+        live_photo
+    ])?;
+
+    Ok(())
+}
+
+/// Add an operation to the journal, so that it can be undone.
+fn record_operation(
+    db_connection: &Connection,
+    run_id: &str,
+    source: &Path,
+    destination: Option<&str>,
+    operation: &str,
+) -> Result<(), Error> {
+    db_connection
+        .prepare_cached(
+            "INSERT INTO operations (run_id, source, destination, operation) VALUES (?, ?, ?, ?)",
+        )?
+        .insert(rusqlite::params![
+            run_id,
+            &get_sql_safe_filename(source)?,
+            destination,
+            operation
+        ])?;
+
+    Ok(())
+}
+
+/// Record a completed transfer in the DB along with its journal entries, all at once so that the DB never
+/// holds half of it.
+fn record_transfer(
+    db_connection: &Connection,
+    planned_transfer: &PlannedTransfer,
+    completed_transfer: &CompletedTransfer,
+    run_id: &str,
+) -> Result<(), Error> {
+    let transaction = db_connection.unchecked_transaction()?;
+
+    let destination = get_sql_safe_filename(&std::path::absolute(&planned_transfer.destination)?)?;
+
+    record_file(
+        &transaction,
+        &planned_transfer.file,
+        Some(completed_transfer.transfer_mode),
+        Some(&destination),
+        completed_transfer.destination_checksum.as_deref(),
+        run_id,
+    )?;
+    record_operation(
+        &transaction,
+        run_id,
+        &planned_transfer.file.path,
+        Some(&destination),
+        completed_transfer.transfer_mode.as_str(),
+    )?;
+
+    if let Some(extracted_video) = &completed_transfer.extracted_video {
+        record_operation(
+            &transaction,
+            run_id,
+            &planned_transfer.file.path,
+            Some(&get_sql_safe_filename(&std::path::absolute(
+                extracted_video,
+            )?)?),
+            EXTRACT_OPERATION,
+        )?;
+    }
+
+    transaction.commit()?;
+
+    Ok(())
+}
+
+/// Process all filenames, copying them if not already copied and if it is possible to determine a valid
+/// date to use for output filename formatting.
+///
+/// Work is split into stages so that the slow parts can run across a pool of `jobs` threads while keeping
+/// results deterministic. Groups are analysed in parallel, then output names are allocated to the files in
+/// each group, one set at a time in a stable order, and the transfers themselves run in parallel. The DB is
+/// only updated from this thread, recording each transfer as it completes.
+fn process_files(
+    db_connection: &Connection,
+    mut filenames: HashMap<GroupKey, Vec<PathBuf>>,
+    renamer_config: &RenamerConfig,
+    renamer_args: &RenamerArgs,
+    jobs: usize,
+) -> Result<(), Error> {
+    info!("Beginning media rename operation...");
+
+    // Identifies everything done by this run in the journal, so that it can be undone
+    let run_id = Local::now().format("%Y%m%d_%H%M%S_%3f").to_string();
+
+    let thread_pool = rayon::ThreadPoolBuilder::new().num_threads(jobs).build()?;
+
+    let mut errors: Vec<String> = vec![];
+
+    // Firstly, work out which files still need processing, and gather up any Live Photos split across groups.
+    // Groups where everything has been processed already are skipped, and the rest are sorted so that name
+    // allocation is the same from run to run.
+    let pending_paths = find_pending_paths(Some(db_connection), &filenames);
+    regroup_live_photo_videos(&mut filenames, &pending_paths, &thread_pool);
+
+    let mut group_keys = filenames.keys().collect::<Vec<&GroupKey>>();
+    group_keys.sort();
+
+    let mut pending_groups: Vec<(&Vec<PathBuf>, Vec<&PathBuf>)> = vec![];
+
+    for group_key in group_keys {
+        let paths = &filenames[group_key];
+
+        let mut group_pending_paths = paths
+            .iter()
+            .filter(|path| pending_paths.contains(*path))
+            .collect::<Vec<&PathBuf>>();
+        group_pending_paths.sort();
+
+        if !group_pending_paths.is_empty() {
+            pending_groups.push((paths, group_pending_paths));
+        }
+    }
+
+    // Next, read dates, checksums and the like for every pending group across the thread pool.
+    let pb = get_progress_bar(pending_groups.len() as u64)?;
+
+    let analysed_groups = thread_pool.install(|| {
+        pending_groups
+            .par_iter()
+            .map(|(paths, pending_paths)| {
+                let analysis = analyse_group(paths, pending_paths, renamer_config);
+                pb.inc(1);
+                analysis
+            })
+            .collect::<Vec<Vec<Result<AnalysedFile, String>>>>()
+    });
+
+    pb.finish_and_clear();
+
+    // Allocate output paths in order. If we've seen the exact contents of a file before, possibly from a
+    // different card or mount point, or earlier in this run, there's no point copying them again. Duplicates
+    // are still recorded against their checksum so we don't need to re-hash them next time.
+    let mut reserved_paths: HashSet<PathBuf> = HashSet::new();
+    let mut seen_checksums: HashSet<String> = HashSet::new();
+    let mut planned_transfers: Vec<PlannedTransfer> = vec![];
+    let mut duplicates: Vec<AnalysedFile> = vec![];
+
+    let mut pairings: Vec<Vec<AnalysedFile>> = vec![];
+
+    for analysed_group in analysed_groups {
+        let mut analysed_files = vec![];
+
+        for analysed_file in analysed_group {
+            match analysed_file {
+                Err(error) => errors.push(error),
+                Ok(analysed_file) => analysed_files.push(analysed_file),
+            }
+        }
+
+        pairings.extend(pair_group_files(analysed_files));
+    }
+
+    // Allocate names in date order, so that burst shots taken within the same second pick up their collision
+    // counters in the order they were taken, according to their sub-seconds.
+    pairings.sort_by(|a, b| (a[0].date.local, &a[0].path).cmp(&(b[0].date.local, &b[0].path)));
+
+    // Note any files whose date sources disagree by more than the threshold, as they're worth checking by hand
+    let mut disagreements: Vec<String> = vec![];
+
+    if let Some(threshold) = &renamer_config.date_disagreement_threshold {
+        for analysed_file in pairings.iter().flatten() {
+            for candidate in &analysed_file.date_candidates {
+                if candidate.difference.abs() > threshold.0.abs() {
+                    disagreements.push(format!(
+                        "{} was dated {} from {}, but {} gives {}",
+                        analysed_file.path.to_str().unwrap(),
+                        analysed_file.date,
+                        analysed_file.date_source,
+                        candidate.source,
+                        candidate.date
+                    ));
+                }
+            }
+        }
+    }
+
+    for pairing in pairings {
+        let mut pending_files: Vec<AnalysedFile> = vec![];
+
+        for analysed_file in pairing {
+            // Sidecars are skipped along with their primary, rather than on their own contents, as identical
+            // sidecars can belong to different files
+            let is_duplicate = match &analysed_file.parent {
+                Some(parent) => duplicates.iter().any(|duplicate| &duplicate.path == parent),
+                None => {
+                    seen_checksums.contains(&analysed_file.checksum)
+                        || has_checksum_been_processed(db_connection, &analysed_file.checksum)?
+                }
+            };
+
+            if is_duplicate {
+                if renamer_args.test_mode {
+                    info!(
+                        "Would have skipped {} as a duplicate of previously copied content",
+                        analysed_file.path.to_str().unwrap()
+                    );
+                } else {
+                    info!(
+                        "Skipping {} as a duplicate of previously copied content",
+                        analysed_file.path.to_str().unwrap()
+                    );
+                }
+
+                duplicates.push(analysed_file);
+                continue;
+            }
+
+            if analysed_file.parent.is_none() {
+                seen_checksums.insert(analysed_file.checksum.clone());
+            }
+
+            pending_files.push(analysed_file);
+        }
+
+        if pending_files.is_empty() {
+            continue;
+        }
+
+        let destinations = match allocate_output_paths(
+            &pending_files.iter().collect::<Vec<&AnalysedFile>>(),
+            renamer_config,
+            &mut reserved_paths,
+        ) {
+            None => {
+                for analysed_file in &pending_files {
+                    errors.push(format!(
+                        "Unable to find a free output filename for {}",
+                        analysed_file.path.to_str().unwrap()
+                    ));
+                }
+                continue;
+            }
+            Some(destinations) => destinations,
+        };
+
+        for (analysed_file, destination) in pending_files.into_iter().zip(destinations) {
+            if renamer_args.test_mode {
+                info!(
+                    "Would have transferred ({}) {} to {}",
+                    renamer_config.transfer_mode,
+                    analysed_file.path.to_str().unwrap(),
+                    destination.to_str().unwrap()
+                );
+
+                if analysed_file.embedded_video.is_some() {
+                    info!(
+                        "Would have extracted the motion photo video from {} to {}",
+                        analysed_file.path.to_str().unwrap(),
+                        motion_photo_video_path(&destination).to_str().unwrap()
+                    );
+                }
+                continue;
+            }
+
+            planned_transfers.push(PlannedTransfer {
+                file: analysed_file,
+                destination,
+            });
+        }
+    }
+
+    let mut successful_file_copy_count = 0;
+    let mut extracted_video_count = 0;
+    let duplicate_file_count = duplicates.len();
+
+    // Duplicates aren't transferred, but are recorded against their checksum, and journalled so that undoing
+    // this run forgets them too
+    if !renamer_args.test_mode && !duplicates.is_empty() {
+        let transaction = db_connection.unchecked_transaction()?;
+
+        for duplicate in &duplicates {
+            record_file(&transaction, duplicate, None, None, None, &run_id)?;
+            record_operation(
+                &transaction,
+                &run_id,
+                &duplicate.path,
+                None,
+                DUPLICATE_OPERATION,
+            )?;
+        }
+
+        transaction.commit()?;
+    }
+
+    // Now perform the transfers themselves, again across the thread pool. Each is sent back to this thread to
+    // be recorded in the DB as soon as it's finished, so that an interrupted run still knows about everything
+    // it transferred.
+    let pb = get_progress_bar(planned_transfers.len() as u64)?;
+
+    // Set if recording fails, so that no more transfers are started which couldn't be recorded or undone
+    let stopped = AtomicBool::new(false);
+
+    let recording_result = thread_pool.in_place_scope(|scope| -> Result<(), Error> {
+        let (sender, receiver) = mpsc::channel();
+
+        for planned_transfer in &planned_transfers {
+            let sender = sender.clone();
+            let stopped = &stopped;
+
+            scope.spawn(move |_| {
+                if stopped.load(Ordering::Relaxed) {
+                    return;
+                }
+
+                let transfer_result = transfer_planned_file(planned_transfer, renamer_config);
+                let _ = sender.send((planned_transfer, transfer_result));
+            });
+        }
+
+        drop(sender);
+
+        for (planned_transfer, transfer_result) in receiver {
+            pb.inc(1);
+
+            match transfer_result {
+                Err(e) => errors.push(format!(
+                    "Unable to transfer {} to {}: {}",
+                    planned_transfer.file.path.to_str().unwrap(),
+                    planned_transfer.destination.to_str().unwrap(),
+                    e
+                )),
+                Ok(completed_transfer) => {
+                    if let Err(e) = record_transfer(
+                        db_connection,
+                        planned_transfer,
+                        &completed_transfer,
+                        &run_id,
+                    ) {
+                        stopped.store(true, Ordering::Relaxed);
+                        return Err(e);
+                    }
+
+                    if completed_transfer.extracted_video.is_some() {
+                        extracted_video_count += 1;
+                    }
+
+                    successful_file_copy_count += 1;
+                }
+            }
+        }
+
+        Ok(())
+    });
+
+    pb.finish_and_clear();
+    recording_result?;
+
+    if !renamer_args.test_mode && (successful_file_copy_count > 0 || duplicate_file_count > 0) {
+        info!("Run id {}", run_id);
+    }
+
+    if !disagreements.is_empty() {
+        for disagreement in &disagreements {
+            warn!("{}", disagreement);
+        }
+
+        warn!(
+            "Found {} dates from other sources that disagree by more than {}",
+            disagreements.len(),
+            renamer_config.date_disagreement_threshold.unwrap()
+        );
+    }
+
     // Finally, write out the errors to disk.
     if !errors.is_empty() {
         if let Ok(mut config_file) =
             File::create(Local::now().format("%Y%m%d_%H%M%S_errors.log").to_string())
         {
-            config_file.write(&errors.join("\n").as_bytes())?;
+            config_file.write_all(errors.join("\n").as_bytes())?;
         }
 
         warn!("Errors found when copying {} files", errors.len());
@@ -489,6 +1869,32 @@ fn process_files(
         info!("Copied {} files", successful_file_copy_count);
     }
 
+    if extracted_video_count > 0 {
+        info!("Extracted {} motion photo videos", extracted_video_count);
+    }
+
+    if duplicate_file_count > 0 {
+        info!(
+            "Skipped {} files with previously copied content",
+            duplicate_file_count
+        );
+    }
+
+    let mtime_dated_count = planned_transfers
+        .iter()
+        .filter(|planned_transfer| {
+            planned_transfer.file.date_source == DateSource::Mtime
+                && planned_transfer.file.parent.is_none()
+        })
+        .count();
+
+    if mtime_dated_count > 0 {
+        warn!(
+            "Dated {} files from their modified time, run the report subcommand to list them",
+            mtime_dated_count
+        );
+    }
+
     Ok(())
 }
 
@@ -504,14 +1910,16 @@ fn process_rebase(args: &RenamerArgs, rebase_args: &RebaseSubCommand) -> Result<
         dest_root = dest_root.add(MAIN_SEPARATOR_STR);
     }
 
-    let db_connection = get_db(&args)?;
+    let db_connection = get_db(args)?;
 
     if args.test_mode {
         let mut select_statement =
             db_connection.prepare("SELECT COUNT(*) FROM files WHERE filename like ?")?;
 
-        let updated_rows =
-            select_statement.query_row(rusqlite::params![format!("{}%", &source_root)], |row| row.get::<_, i32>(0))?;
+        let updated_rows = select_statement
+            .query_row(rusqlite::params![format!("{}%", &source_root)], |row| {
+                row.get::<_, i32>(0)
+            })?;
 
         info!(
             "Would have updated {} path roots from {} to {}",
@@ -527,6 +1935,24 @@ fn process_rebase(args: &RenamerArgs, rebase_args: &RebaseSubCommand) -> Result<
             format!("{}%", &source_root)
         ])?;
 
+        // Sidecars refer to their primary by its source path, so those need to follow it
+        db_connection.execute(
+            "UPDATE files SET parent = replace(parent, ?1, ?2) WHERE parent like ?3",
+            rusqlite::params![&source_root, &dest_root, format!("{}%", &source_root)],
+        )?;
+
+        // Likewise for the other half of Live Photo pairs
+        db_connection.execute(
+            "UPDATE files SET live_photo = replace(live_photo, ?1, ?2) WHERE live_photo like ?3",
+            rusqlite::params![&source_root, &dest_root, format!("{}%", &source_root)],
+        )?;
+
+        // Keep the journal in step, so that undoing a move still restores files to the right place
+        db_connection.execute(
+            "UPDATE operations SET source = replace(source, ?1, ?2) WHERE source like ?3",
+            rusqlite::params![&source_root, &dest_root, format!("{}%", &source_root)],
+        )?;
+
         info!(
             "Updated path roots from {} to {} - {} rows affected",
             &source_root, &dest_root, updated_rows
@@ -538,8 +1964,376 @@ fn process_rebase(args: &RenamerArgs, rebase_args: &RebaseSubCommand) -> Result<
     Ok(())
 }
 
-fn process_rename(args: &RenamerArgs, _: &RenameSubCommand) -> Result<(), Error> {
-    // Try and read config file into object. If none was found, this will be None, so we can finish up
+/// Reverse a single journalled operation: outputs that were copied or linked are removed, and moved files
+/// are moved back to where they came from.
+fn undo_operation(
+    source: &Path,
+    destination: Option<&Path>,
+    operation: &str,
+    checksum: Option<&str>,
+) -> Result<(), Error> {
+    let destination = match destination {
+        // Duplicates were never transferred, so there's nothing on disk to undo
+        None => return Ok(()),
+        Some(destination) => destination,
+    };
+
+    // Videos extracted from motion photos are new files, so are removed like copies
+    let was_moved = operation != EXTRACT_OPERATION
+        && operation.parse::<TransferMode>().map_err(|e| anyhow!(e))? == TransferMode::Move;
+
+    if was_moved {
+        if source.exists() {
+            return Err(anyhow!(
+                "Unable to move {} back as {} already exists",
+                destination.to_str().unwrap(),
+                source.to_str().unwrap()
+            ));
+        }
+
+        fs::create_dir_all(source.parent().unwrap())?;
+        transfer_file(destination, source, TransferMode::Move)?;
+    } else if destination.symlink_metadata().is_ok() {
+        // Unless it's only a symlink, the output may now be the only copy of the file, such as once the card it
+        // came from has been formatted, so only remove it if the source is still there as it was
+        if operation != TransferMode::Symlink.as_str() {
+            if !source.exists() {
+                return Err(anyhow!(
+                    "{} no longer exists, so {} is the only copy",
+                    source.to_str().unwrap(),
+                    destination.to_str().unwrap()
+                ));
+            }
+
+            if checksum
+                .is_some_and(|checksum| get_file_checksum(source).ok().as_deref() != Some(checksum))
+            {
+                return Err(anyhow!(
+                    "{} has changed since it was transferred, so {} may be the only copy",
+                    source.to_str().unwrap(),
+                    destination.to_str().unwrap()
+                ));
+            }
+        }
+
+        fs::remove_file(destination)?;
+    } else {
+        warn!("{} has already been removed", destination.to_str().unwrap());
+    }
+
+    Ok(())
+}
+
+fn process_undo(args: &RenamerArgs, undo_args: &UndoSubCommand) -> Result<(), Error> {
+    let db_connection = get_db(args)?;
+
+    let run_id = match &undo_args.run_id {
+        Some(run_id) => run_id.clone(),
+        None => {
+            let mut select_statement =
+                db_connection.prepare("SELECT MAX(run_id) FROM operations")?;
+
+            match select_statement.query_row((), |row| row.get::<_, Option<String>>(0))? {
+                None => {
+                    info!("No runs found to undo");
+                    return Ok(());
+                }
+                Some(run_id) => run_id,
+            }
+        }
+    };
+
+    // Work backwards through the run, in case anything depends on what came before it
+    let mut select_statement = db_connection.prepare(
+        "SELECT operations.rowid, source, operations.destination, operation, checksum FROM operations \
+         LEFT JOIN files ON files.filename = operations.source WHERE operations.run_id = ? \
+         ORDER BY operations.rowid DESC",
+    )?;
+
+    let operations = select_statement
+        .query_map(rusqlite::params![&run_id], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, Option<String>>(4)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if operations.is_empty() {
+        return Err(anyhow!("No operations found for run {}", run_id));
+    }
+
+    let mut errors: Vec<String> = vec![];
+    let mut undone_count = 0;
+
+    for (rowid, source, destination, operation, checksum) in operations {
+        let source_path = PathBuf::from(&source);
+        let destination_path = destination.as_ref().map(PathBuf::from);
+
+        if args.test_mode {
+            match &destination {
+                None => info!("Would have forgotten duplicate {}", source),
+                Some(destination) if operation == TransferMode::Move.as_str() => {
+                    info!("Would have moved {} back to {}", destination, source)
+                }
+                Some(destination) => info!("Would have removed {}", destination),
+            }
+            continue;
+        }
+
+        if let Err(e) = undo_operation(
+            &source_path,
+            destination_path.as_deref(),
+            &operation,
+            checksum.as_deref(),
+        ) {
+            errors.push(format!("Unable to undo {} of {}: {}", operation, source, e));
+            continue;
+        }
+
+        // Extracted videos are undone before the file they came from, which must stay in the history until
+        // it's been undone too
+        let transaction = db_connection.unchecked_transaction()?;
+
+        if operation != EXTRACT_OPERATION {
+            transaction.execute(
+                "DELETE FROM files WHERE filename = ?",
+                rusqlite::params![&source],
+            )?;
+        }
+
+        transaction.execute(
+            "DELETE FROM operations WHERE rowid = ?",
+            rusqlite::params![rowid],
+        )?;
+        transaction.commit()?;
+
+        undone_count += 1;
+    }
+
+    if !errors.is_empty() {
+        for error in &errors {
+            warn!("{}", error);
+        }
+
+        return Err(anyhow!(
+            "{} errors found when undoing run {}",
+            errors.len(),
+            run_id
+        ));
+    }
+
+    if !args.test_mode {
+        info!("Undid {} operations from run {}", undone_count, run_id);
+    }
+
+    info!("Undo complete");
+
+    Ok(())
+}
+
+/// The result of verifying a single file from the history, written out as one line of JSON.
+#[derive(Serialize)]
+struct VerifyRecord {
+    source: String,
+    destination: Option<String>,
+    transfer_mode: Option<String>,
+    /// The checksum the destination should have, which differs from the source's for stripped motion photos
+    checksum: String,
+    problems: Vec<&'static str>,
+}
+
+/// Check a single row of the history. Moved files are expected to be missing from their source, and
+/// duplicates were never written anywhere, so only have their source checked.
+fn verify_file(
+    source: String,
+    destination: Option<String>,
+    transfer_mode: Option<String>,
+    checksum: String,
+) -> VerifyRecord {
+    let mut problems = vec![];
+
+    let was_moved = transfer_mode.as_deref() == Some(TransferMode::Move.as_str());
+
+    if !was_moved && !Path::new(&source).exists() {
+        problems.push("source_missing");
+    }
+
+    match (&destination, &transfer_mode) {
+        (None, None) => {}
+        // Files processed before destinations were recorded
+        (None, Some(_)) => problems.push("destination_unknown"),
+        (Some(destination), _) => match get_file_checksum(Path::new(destination)) {
+            Err(_) if !Path::new(destination).exists() => problems.push("destination_missing"),
+            Err(_) => problems.push("destination_unreadable"),
+            Ok(destination_checksum) if destination_checksum != checksum => {
+                problems.push("checksum_mismatch")
+            }
+            Ok(_) => {}
+        },
+    }
+
+    VerifyRecord {
+        source,
+        destination,
+        transfer_mode,
+        checksum,
+        problems,
+    }
+}
+
+fn process_verify(args: &RenamerArgs, verify_args: &VerifySubCommand) -> Result<(), Error> {
+    let db_connection = get_db(args)?;
+
+    let mut select_statement = db_connection.prepare(
+        "SELECT filename, destination, transfer_mode, COALESCE(destination_checksum, checksum) FROM files \
+         ORDER BY filename",
+    )?;
+
+    let rows = select_statement
+        .query_map((), |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                row.get::<_, Option<String>>(2)?,
+                row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    info!("Verifying {} files", rows.len());
+
+    let thread_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(verify_args.jobs.unwrap_or(0))
+        .build()?;
+
+    let pb = get_progress_bar(rows.len() as u64)?;
+
+    let records = thread_pool.install(|| {
+        rows.into_par_iter()
+            .map(|(source, destination, transfer_mode, checksum)| {
+                let record = verify_file(source, destination, transfer_mode, checksum);
+                pb.inc(1);
+                record
+            })
+            .collect::<Vec<VerifyRecord>>()
+    });
+
+    pb.finish_and_clear();
+
+    let report_path = match &verify_args.report {
+        Some(report_path) => report_path.clone(),
+        None => Local::now()
+            .format("%Y%m%d_%H%M%S_verify.jsonl")
+            .to_string(),
+    };
+
+    let mut report_file = File::create(&report_path)?;
+    let mut problem_count = 0;
+
+    for record in &records {
+        if !record.problems.is_empty() {
+            problem_count += 1;
+        } else if !verify_args.all {
+            continue;
+        }
+
+        writeln!(report_file, "{}", serde_json::to_string(record)?)?;
+    }
+
+    info!("Verification report written to {}", report_path);
+
+    if problem_count > 0 {
+        warn!("Problems found with {} files", problem_count);
+
+        return Err(anyhow!("{} files failed verification", problem_count));
+    }
+
+    info!("Verify complete, no problems found");
+
+    Ok(())
+}
+
+/// A file from the history, and how its date was determined, written out as one line of JSON.
+#[derive(Serialize)]
+struct DateReportRecord {
+    source: String,
+    destination: Option<String>,
+    date_source: String,
+    date: Option<String>,
+    date_candidates: Option<serde_json::Value>,
+}
+
+fn process_report(args: &RenamerArgs, report_args: &ReportSubCommand) -> Result<(), Error> {
+    let db_connection = get_db(args)?;
+
+    let mut select_statement = db_connection.prepare(
+        "SELECT filename, destination, date_source, date, date_candidates FROM files \
+         WHERE date_source = ? AND parent IS NULL ORDER BY filename",
+    )?;
+
+    let records = select_statement
+        .query_map(rusqlite::params![report_args.date_source.as_str()], |row| {
+            Ok(DateReportRecord {
+                source: row.get(0)?,
+                destination: row.get(1)?,
+                date_source: row.get(2)?,
+                date: row.get(3)?,
+                date_candidates: row
+                    .get::<_, Option<String>>(4)?
+                    .and_then(|candidates| serde_json::from_str(&candidates).ok()),
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let report_path = match &report_args.output {
+        Some(report_path) => report_path.clone(),
+        None => Local::now()
+            .format(&format!(
+                "%Y%m%d_%H%M%S_{}_report.jsonl",
+                report_args.date_source
+            ))
+            .to_string(),
+    };
+
+    let mut report_file = File::create(&report_path)?;
+
+    for record in &records {
+        writeln!(report_file, "{}", serde_json::to_string(record)?)?;
+    }
+
+    info!(
+        "{} files dated from {} written to {}",
+        records.len(),
+        report_args.date_source,
+        report_path
+    );
+
+    Ok(())
+}
+
+/// EXIF fields shown by the explain subcommand, as they may all play a part in dating a file.
+const EXPLAINED_EXIF_TAGS: [Tag; 14] = [
+    Tag::DateTimeOriginal,
+    Tag::SubSecTimeOriginal,
+    Tag::OffsetTimeOriginal,
+    Tag::DateTimeDigitized,
+    Tag::SubSecTimeDigitized,
+    Tag::OffsetTimeDigitized,
+    Tag::DateTime,
+    Tag::SubSecTime,
+    Tag::OffsetTime,
+    Tag::GPSDateStamp,
+    Tag::GPSTimeStamp,
+    Tag::Make,
+    Tag::Model,
+    Tag::BodySerialNumber,
+];
+
+fn process_explain(args: &RenamerArgs, explain_args: &ExplainSubCommand) -> Result<(), Error> {
     let config = match RenamerConfig::read_or_create()? {
         None => {
             return Ok(());
@@ -547,10 +2341,333 @@ fn process_rename(args: &RenamerArgs, _: &RenameSubCommand) -> Result<(), Error>
         Some(conf_object) => conf_object,
     };
 
-    let db_connection = get_db(&args)?;
+    let path = Path::new(&explain_args.path).canonicalize()?;
+    println!("File: {}", path.to_str().unwrap());
+
+    if !file_in_scope(&path) && !_is_sidecar(&path) {
+        println!(
+            "  Not a supported picture, RAW, movie or sidecar file, so would not be processed"
+        );
+    }
+
+    // Explaining shouldn't change anything, so the DB is only read if it's already there
+    let db_connection = match db::open_existing(Path::new(&args.db_name)) {
+        Ok(db_connection) => db_connection,
+        Err(e) => {
+            println!("  {}, so the history isn't checked", e);
+            None
+        }
+    };
+
+    // Dates are shared between files in the same group, so look up the whole group as a rename would. Live
+    // Photo videos may have been moved into their photo's group, so search for the file rather than its key.
+    let mut filenames = get_all_filenames_in_scope(&config)?;
+    let mut pending_paths = find_pending_paths(db_connection.as_ref(), &filenames);
+    pending_paths.insert(path.clone());
+    regroup_live_photo_videos(
+        &mut filenames,
+        &pending_paths,
+        &rayon::ThreadPoolBuilder::new().build()?,
+    );
+
+    let (group_key, group) = match filenames.iter().find(|(_, paths)| paths.contains(&path)) {
+        Some((group_key, paths)) => (group_key.clone(), paths.clone()),
+        None => {
+            println!("  Outside the root paths or excluded, so would not be processed");
+            (
+                GroupKey::new(&path, config.grouping).unwrap(),
+                vec![path.clone()],
+            )
+        }
+    };
+
+    // The file is analysed alongside the rest of its group that's still to be processed, as they share a date.
+    // Sidecars take their date from their primary, so that's always included.
+    let sidecar_primary = match _is_sidecar(&path) {
+        true => find_sidecar_primary(&path, &group),
+        false => None,
+    };
+
+    let mut explained_paths = group
+        .iter()
+        .filter(|other_path| {
+            **other_path == path
+                || Some(*other_path) == sidecar_primary
+                || pending_paths.contains(*other_path)
+        })
+        .collect::<Vec<&PathBuf>>();
+    explained_paths.sort();
+
+    if _is_sidecar(&path) {
+        match sidecar_primary {
+            Some(primary) => {
+                println!(
+                    "  A sidecar of {}, so takes its date and name from it",
+                    primary.to_str().unwrap()
+                );
+            }
+            None => println!(
+                "  A sidecar with no matching picture, RAW or movie file, so would not be processed"
+            ),
+        }
+    }
+
+    if let Some(pair) = find_live_photo_pairs(&group)
+        .into_iter()
+        .find(|pair| pair.photo == path || pair.video == path)
+    {
+        let other_half = match pair.photo == path {
+            true => &pair.video,
+            false => &pair.photo,
+        };
+
+        match &pair.content_identifier {
+            Some(content_identifier) => println!(
+                "  Part of a Live Photo with {}, sharing the content identifier {}",
+                other_half.to_str().unwrap(),
+                content_identifier
+            ),
+            None => println!(
+                "  Part of a Live Photo with {}, matched by name",
+                other_half.to_str().unwrap()
+            ),
+        }
+    }
+
+    // Dates outside the plausible range are shown, but marked as they'll be skipped over
+    let implausible_note = |date: &CaptureTime| match config.is_plausible(date) {
+        true => "",
+        false => " (outside the plausible range, ignored)",
+    };
+
+    println!();
+    println!("Files grouped as \"{}\":", group_key);
+
+    for sibling in &group {
+        match extract_timestamp_from_metadata(sibling, &config) {
+            Ok(date) => println!(
+                "  {}: {}{}",
+                sibling.to_str().unwrap(),
+                date,
+                implausible_note(&date)
+            ),
+            Err(e) => println!("  {}: no date ({})", sibling.to_str().unwrap(), e),
+        }
+    }
+
+    println!();
+
+    if _is_movie(&path) {
+        println!("QuickTime metadata:");
+
+        match quicktime::extract_creation_time(&path) {
+            Ok(date) => println!("  Creation time: {}", date),
+            Err(e) => println!("  {}", e),
+        }
+    } else {
+        println!("EXIF fields:");
+
+        match read_exif(&path) {
+            Err(e) => println!("  {}", e),
+            Ok(exif_data) => {
+                for tag in EXPLAINED_EXIF_TAGS {
+                    if let Some(field) = exif_data.get_field(tag, In::PRIMARY) {
+                        println!("  {}: {}", tag, field.display_value().with_unit(&exif_data));
+                    }
+                }
+            }
+        }
+
+        if !_is_heif(&path) {
+            match motion_photo::find_embedded_video(&path) {
+                Ok(Some(embedded_video)) => println!(
+                    "  Motion photo video: {} bytes at offset {}, which is {}",
+                    embedded_video.length,
+                    embedded_video.offset,
+                    match config.motion_photos {
+                        MotionPhotoMode::Keep => "kept in the still",
+                        MotionPhotoMode::Extract => "extracted next to the still",
+                        MotionPhotoMode::Split => "extracted and stripped from the still",
+                    }
+                ),
+                Ok(None) => {}
+                Err(e) => println!("  {}", e),
+            }
+        }
+    }
+
+    println!();
+    println!("Other sources:");
+
+    match extract_datetime_from_filename(&path, &config) {
+        Some((date, pattern)) => println!(
+            "  Filename: {} (matched {}){}",
+            date,
+            pattern.name,
+            implausible_note(&date)
+        ),
+        None => println!("  Filename: no date found"),
+    }
+
+    match extract_datetime_from_directory(&path, &config) {
+        Some((date, pattern, name)) => println!(
+            "  Directory: {} (matched {} in \"{}\"){}",
+            date,
+            pattern.name,
+            name,
+            implausible_note(&date)
+        ),
+        None => println!("  Directory: no date found"),
+    }
+
+    match extract_datetime_from_file_metadata(&path) {
+        Some(date) => println!("  Modified time: {}{}", date, implausible_note(&date)),
+        None => println!("  Modified time: unavailable"),
+    }
+
+    println!();
+
+    let (analysed_files, errors): (Vec<_>, Vec<_>) =
+        analyse_group(&group, &explained_paths, &config)
+            .into_iter()
+            .partition(|analysed_file| analysed_file.is_ok());
+
+    let mut pairing = match pair_group_files(analysed_files.into_iter().flatten().collect())
+        .into_iter()
+        .find(|pairing| pairing.iter().any(|paired_file| paired_file.path == path))
+    {
+        Some(pairing) => pairing,
+        None => {
+            // Sidecars aren't given an error of their own when their primary can't be dated
+            let error = errors
+                .iter()
+                .flat_map(|error| error.as_ref().err())
+                .min_by_key(|error| !error.contains(path.to_str().unwrap()));
+
+            if let Some(error) = error {
+                println!("Result: {}", error);
+            }
+
+            return Ok(());
+        }
+    };
+
+    let analysed_file = pairing
+        .iter()
+        .find(|paired_file| paired_file.path == path)
+        .unwrap();
+
+    println!(
+        "Date sources, in order: {}",
+        config
+            .date_sources_for(&path)
+            .iter()
+            .map(|date_source| date_source.as_str())
+            .collect::<Vec<&str>>()
+            .join(", ")
+    );
+
+    println!(
+        "Result: {} from {}",
+        analysed_file.date, analysed_file.date_source
+    );
+
+    if let Some(clock_offset) = config.camera_offset(
+        analysed_file.camera.make.as_deref(),
+        analysed_file.camera.model.as_deref(),
+        analysed_file.camera.serial.as_deref(),
+    ) {
+        println!("  Includes a camera clock correction of {}", clock_offset);
+    }
+
+    if let Some(target_timezone) = &config.target_timezone {
+        println!(
+            "  Converted to {} where the offset is known",
+            target_timezone
+        );
+    }
+
+    for candidate in &analysed_file.date_candidates {
+        println!(
+            "  Disagrees with {} from {}",
+            candidate.date, candidate.source
+        );
+    }
+
+    let recorded_destination = match &db_connection {
+        None => Err(rusqlite::Error::QueryReturnedNoRows),
+        Some(db_connection) => db_connection
+            .prepare("SELECT destination FROM files WHERE filename = ?")?
+            .query_row(rusqlite::params![get_sql_safe_filename(&path)?], |row| {
+                row.get::<_, Option<String>>(0)
+            }),
+    };
+
+    let has_checksum_been_processed = |checksum: &str| match &db_connection {
+        None => Ok(false),
+        Some(db_connection) => has_checksum_been_processed(db_connection, checksum),
+    };
+
+    match recorded_destination {
+        Ok(Some(destination)) => println!("Already processed, to {}", destination),
+        Ok(None) => {
+            println!("Already processed, as a duplicate or before output paths were recorded")
+        }
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            if has_checksum_been_processed(&analysed_file.checksum)? {
+                println!("Not yet processed, but its contents have been, so would be skipped as a duplicate");
+            } else {
+                // The other pending files in the group it's paired with are named alongside it, unless they're
+                // duplicates which would be skipped
+                pairing.retain(|paired_file| {
+                    paired_file.path == path
+                        || !has_checksum_been_processed(&paired_file.checksum).unwrap_or(false)
+                });
+
+                let paired_files = pairing.iter().collect::<Vec<&AnalysedFile>>();
+                let index = paired_files
+                    .iter()
+                    .position(|paired_file| paired_file.path == path)
+                    .unwrap();
+
+                match allocate_output_paths(&paired_files, &config, &mut HashSet::new()) {
+                    Some(destinations) => println!(
+                        "Not yet processed, would be written to {}",
+                        destinations[index].to_str().unwrap()
+                    ),
+                    None => println!("Not yet processed, and no free output filename is available"),
+                }
+            }
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    Ok(())
+}
+
+fn process_rename(args: &RenamerArgs, rename_args: &RenameSubCommand) -> Result<(), Error> {
+    // Try and read config file into object. If none was found, this will be None, so we can finish up
+    let mut config = match RenamerConfig::read_or_create()? {
+        None => {
+            return Ok(());
+        }
+        Some(conf_object) => conf_object,
+    };
+
+    if let Some(transfer_mode) = rename_args.transfer_mode {
+        config.transfer_mode = transfer_mode;
+    }
+
+    let db_connection = get_db(args)?;
     let filenames = get_all_filenames_in_scope(&config)?;
 
-    process_files(&db_connection, &filenames, &config, &args)?;
+    process_files(
+        &db_connection,
+        filenames,
+        &config,
+        args,
+        rename_args.jobs.unwrap_or(0),
+    )?;
 
     info!("Rename complete");
 
@@ -561,8 +2678,12 @@ fn run() -> Result<(), Error> {
     let args: RenamerArgs = argh::from_env();
 
     match args.sub_command {
-        SubCommandEnum::Rename(ref rename_args) => process_rename(&args, &rename_args),
-        SubCommandEnum::Rebase(ref rebase_args) => process_rebase(&args, &rebase_args),
+        SubCommandEnum::Rename(ref rename_args) => process_rename(&args, rename_args),
+        SubCommandEnum::Rebase(ref rebase_args) => process_rebase(&args, rebase_args),
+        SubCommandEnum::Undo(ref undo_args) => process_undo(&args, undo_args),
+        SubCommandEnum::Verify(ref verify_args) => process_verify(&args, verify_args),
+        SubCommandEnum::Report(ref report_args) => process_report(&args, report_args),
+        SubCommandEnum::Explain(ref explain_args) => process_explain(&args, explain_args),
     }?;
 
     Ok(())
@@ -575,3 +2696,150 @@ fn main() -> Result<(), Error> {
 
     exit(0)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn analysed_file(path: &str, parent: Option<&str>) -> AnalysedFile {
+        let date = NaiveDate::from_ymd_opt(2020, 1, 2)
+            .unwrap()
+            .and_hms_opt(3, 4, 5)
+            .unwrap();
+
+        AnalysedFile {
+            path: PathBuf::from(path),
+            date: CaptureTime::naive(date),
+            date_source: DateSource::Metadata,
+            date_candidates: vec![],
+            checksum: String::new(),
+            size: 0,
+            modified: None,
+            camera: CameraDetails::default(),
+            parent: parent.map(PathBuf::from),
+            embedded_video: None,
+            live_photo: None,
+        }
+    }
+
+    /// A config writing into empty directories of its own, so that only reserved paths collide.
+    fn test_config(name: &str) -> RenamerConfig {
+        let directory = std::env::temp_dir()
+            .join(format!("photo_renamer_bin_tests_{}", std::process::id()))
+            .join(name);
+        let _ = fs::remove_dir_all(&directory);
+
+        RenamerConfig {
+            output_path: directory.join("output").to_str().unwrap().to_string(),
+            raw_output_path: directory.join("output_raw").to_str().unwrap().to_string(),
+            ..RenamerConfig::new()
+        }
+    }
+
+    fn paths(pairing: &[AnalysedFile]) -> Vec<&str> {
+        pairing
+            .iter()
+            .map(|analysed_file| analysed_file.path.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn pairs_files_with_different_extensions() {
+        let pairings = pair_group_files(vec![
+            analysed_file("a/IMG_0001.JPG", None),
+            analysed_file("a/IMG_0001.CR2", None),
+            analysed_file("b/IMG_0001.jpg", None),
+            analysed_file("a/IMG_0001.xmp", Some("a/IMG_0001.CR2")),
+            analysed_file("b/IMG_0001.xmp", Some("b/IMG_0001.jpg")),
+        ]);
+
+        let pairings = pairings
+            .iter()
+            .map(|pairing| paths(pairing))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            pairings,
+            vec![
+                vec!["a/IMG_0001.JPG", "a/IMG_0001.CR2", "a/IMG_0001.xmp"],
+                vec!["b/IMG_0001.jpg", "b/IMG_0001.xmp"],
+            ]
+        );
+    }
+
+    #[test]
+    fn allocates_one_counter_for_a_pairing() {
+        let config = test_config("pairing");
+        let jpeg = analysed_file("a/IMG_0001.JPG", None);
+        let raw = analysed_file("a/IMG_0001.CR2", None);
+        let sidecar = analysed_file("a/IMG_0001.CR2.xmp", Some("a/IMG_0001.CR2"));
+        let mut reserved_paths = HashSet::new();
+
+        let first =
+            allocate_output_paths(&[&jpeg, &raw, &sidecar], &config, &mut reserved_paths).unwrap();
+
+        assert_eq!(
+            first,
+            vec![
+                Path::new(&config.output_path).join("20200102_030405.jpg"),
+                Path::new(&config.raw_output_path).join("20200102_030405.cr2"),
+                Path::new(&config.raw_output_path).join("20200102_030405.cr2.xmp"),
+            ]
+        );
+
+        // Only the RAW's name is taken by the first pairing, but both files move on to the next counter
+        let other_raw = analysed_file("b/IMG_0001.CR2", None);
+        let other_jpeg = analysed_file("b/IMG_0001.JPG", None);
+        reserved_paths.remove(&first[0]);
+
+        let second =
+            allocate_output_paths(&[&other_raw, &other_jpeg], &config, &mut reserved_paths)
+                .unwrap();
+
+        assert_eq!(
+            second,
+            vec![
+                Path::new(&config.raw_output_path).join("20200102_030405.1.cr2"),
+                Path::new(&config.output_path).join("20200102_030405.1.jpg"),
+            ]
+        );
+    }
+
+    #[test]
+    fn allocates_names_for_many_files_from_one_day() {
+        let config = test_config("whatsapp");
+        let files = (1..=150)
+            .map(|index| analysed_file(&format!("a/IMG-20200102-WA{:04}.jpg", index), None))
+            .collect::<Vec<AnalysedFile>>();
+        let mut reserved_paths = HashSet::new();
+
+        for analysed_file in &files {
+            assert!(
+                allocate_output_paths(&[analysed_file], &config, &mut reserved_paths).is_some()
+            );
+        }
+
+        assert_eq!(reserved_paths.len(), files.len());
+    }
+
+    #[test]
+    fn allocation_avoids_existing_files() {
+        let config = test_config("existing");
+        fs::create_dir_all(&config.output_path).unwrap();
+        fs::write(
+            Path::new(&config.output_path).join("20200102_030405.jpg"),
+            b"",
+        )
+        .unwrap();
+
+        let jpeg = analysed_file("a/IMG_0001.JPG", None);
+
+        assert_eq!(
+            allocate_output_paths(&[&jpeg], &config, &mut HashSet::new()),
+            Some(vec![
+                Path::new(&config.output_path).join("20200102_030405.1.jpg")
+            ])
+        );
+    }
+}
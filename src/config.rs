@@ -1,12 +1,107 @@
+use std::collections::BTreeMap;
+use std::fmt;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::path::Path;
+use std::str::FromStr;
 
+use chrono::{Duration, Local, NaiveDate};
 use serde_derive::{Deserialize, Serialize};
 use toml;
 
+use crate::directory::{default_directory_patterns, DirectoryDateDefaults, DirectoryPattern};
+use crate::filename::{default_filename_patterns, FilenamePattern};
+use crate::group::GroupingRule;
+use crate::motion_photo::MotionPhotoMode;
+use crate::template::{DirectoryTemplate, FilenameTemplate};
+use crate::timestamp::{
+    default_exif_date_fields, CaptureTime, ConfigDuration, DateSource, ExifDateField,
+    TargetTimezone,
+};
+
 static CONFIG_FILENAME: &str = "renamer.toml";
 
+/// How files are transferred from their source location into the output folders.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferMode {
+    /// Copy the file, leaving the source untouched
+    #[default]
+    Copy,
+    /// Move the file, removing it from the source location
+    Move,
+    /// Create a hard link to the source, which must be on the same file system as the output
+    Hardlink,
+    /// Create a symbolic link pointing at the source
+    Symlink,
+    /// Create a copy-on-write clone where the file system supports it, otherwise fall back to a copy
+    Reflink,
+}
+
+impl TransferMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransferMode::Copy => "copy",
+            TransferMode::Move => "move",
+            TransferMode::Hardlink => "hardlink",
+            TransferMode::Symlink => "symlink",
+            TransferMode::Reflink => "reflink",
+        }
+    }
+}
+
+impl fmt::Display for TransferMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for TransferMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "copy" => Ok(TransferMode::Copy),
+            "move" => Ok(TransferMode::Move),
+            "hardlink" => Ok(TransferMode::Hardlink),
+            "symlink" => Ok(TransferMode::Symlink),
+            "reflink" => Ok(TransferMode::Reflink),
+            _ => Err(format!(
+                "unknown transfer mode '{}', expected one of copy, move, hardlink, symlink or reflink",
+                s
+            )),
+        }
+    }
+}
+
+/// A correction for a camera whose clock is wrong, matched against the EXIF Make, Model and BodySerialNumber
+/// of each file. Any of these left out match everything.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CameraClockOffset {
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+
+    /// Amount to add to dates from this camera, e.g. `+00:03:12` or `-1h`
+    pub offset: ConfigDuration,
+}
+
+impl CameraClockOffset {
+    fn matches(&self, make: Option<&str>, model: Option<&str>, serial: Option<&str>) -> bool {
+        [
+            (&self.make, make),
+            (&self.model, model),
+            (&self.serial, serial),
+        ]
+        .iter()
+        .all(|(wanted, actual)| match (wanted, actual) {
+            (None, _) => true,
+            (Some(wanted), Some(actual)) => wanted.trim().eq_ignore_ascii_case(actual.trim()),
+            (Some(_), None) => false,
+        })
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RenamerConfig {
     /// Which paths will be searched for images and videos
@@ -20,6 +115,105 @@ pub struct RenamerConfig {
 
     /// Path fragments to exclude from processing
     pub exclusions: Vec<String>,
+
+    /// Template used to build output filenames, validated when the config is loaded
+    #[serde(default)]
+    pub filename_template: FilenameTemplate,
+
+    /// Template for the directory hierarchy created under the output paths, e.g. `{year}/{year}-{month}`.
+    /// Files are written directly into the output paths if empty.
+    #[serde(default)]
+    pub directory_template: DirectoryTemplate,
+
+    /// How files are transferred into the output paths: copy, move, hardlink, symlink or reflink
+    #[serde(default)]
+    pub transfer_mode: TransferMode,
+
+    /// Time zone to convert all dates into before naming, e.g. `UTC`, `+01:00` or `Europe/London`. Dates with
+    /// no known offset are left as they are. If not set, dates keep the time zone they were taken in.
+    #[serde(default)]
+    pub target_timezone: Option<TargetTimezone>,
+
+    /// Should output filenames be converted to lowercase after the template is applied?
+    #[serde(default = "default_lowercase_filenames")]
+    pub lowercase_filenames: bool,
+
+    /// How files are grouped to share a date: by stem within a directory, or by stem across all directories
+    #[serde(default)]
+    pub grouping: GroupingRule,
+
+    /// What to do with the video inside Google Motion Photos: keep it, extract it, or extract and strip it
+    #[serde(default)]
+    pub motion_photos: MotionPhotoMode,
+
+    /// Where to look for dates, in order of preference: metadata, filename, directory and mtime
+    #[serde(default = "default_date_sources")]
+    pub date_sources: Vec<DateSource>,
+
+    /// EXIF fields to read dates from, in order of preference: DateTimeOriginal, DateTimeDigitized, DateTime
+    /// and GPS
+    #[serde(default = "default_exif_date_fields")]
+    pub exif_date_fields: Vec<ExifDateField>,
+
+    /// Dates before this are assumed to come from a camera with a reset clock, and are ignored
+    #[serde(default = "default_min_date")]
+    pub min_date: NaiveDate,
+
+    /// Dates after this are ignored. If not set, dates after tomorrow are ignored.
+    #[serde(default)]
+    pub max_date: Option<NaiveDate>,
+
+    /// If set, files where another source's date is further than this from the one used, e.g. `1d` or
+    /// `02:00:00`, are listed at the end of each run
+    #[serde(default)]
+    pub date_disagreement_threshold: Option<ConfigDuration>,
+
+    /// Clock corrections for individual cameras, applied to their dates before naming. The first matching
+    /// entry is used.
+    #[serde(default)]
+    pub camera_offsets: Vec<CameraClockOffset>,
+
+    /// Rules for finding dates in filenames, tried in order. Each has a `name`, a regex `pattern` whose
+    /// groups are joined and parsed with the chrono `format`, and `utc` if the date is in UTC.
+    #[serde(default = "default_filename_patterns")]
+    pub filename_patterns: Vec<FilenamePattern>,
+
+    /// Rules for finding dates in the names of the directories holding files, tried in order against each
+    /// directory from the nearest upwards. Each has a `name` and a regex `pattern` with a `year` group, and
+    /// optionally `month` and `day` groups.
+    #[serde(default = "default_directory_patterns")]
+    pub directory_patterns: Vec<DirectoryPattern>,
+
+    /// The `month`, `day` and `time` used for any parts of a date missing from a directory name
+    #[serde(default)]
+    pub directory_date_defaults: DirectoryDateDefaults,
+
+    /// Overrides of `date_sources` for particular file extensions, e.g. `mp4 = ["filename", "mtime"]`
+    #[serde(default)]
+    pub date_sources_by_extension: BTreeMap<String, Vec<DateSource>>,
+}
+
+fn default_lowercase_filenames() -> bool {
+    true
+}
+
+fn default_min_date() -> NaiveDate {
+    NaiveDate::from_ymd_opt(1990, 1, 1).unwrap()
+}
+
+fn default_date_sources() -> Vec<DateSource> {
+    vec![
+        DateSource::Metadata,
+        DateSource::Filename,
+        DateSource::Directory,
+        DateSource::Mtime,
+    ]
+}
+
+impl Default for RenamerConfig {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl RenamerConfig {
@@ -37,9 +231,62 @@ impl RenamerConfig {
                 .into_string()
                 .unwrap(),
             exclusions: vec![String::from("exclusions"), String::from("output")],
+            filename_template: FilenameTemplate::default(),
+            directory_template: DirectoryTemplate::default(),
+            transfer_mode: TransferMode::default(),
+            target_timezone: None,
+            lowercase_filenames: default_lowercase_filenames(),
+            grouping: GroupingRule::default(),
+            motion_photos: MotionPhotoMode::default(),
+            date_sources: default_date_sources(),
+            exif_date_fields: default_exif_date_fields(),
+            min_date: default_min_date(),
+            max_date: None,
+            date_disagreement_threshold: None,
+            camera_offsets: vec![],
+            filename_patterns: default_filename_patterns(),
+            directory_patterns: default_directory_patterns(),
+            directory_date_defaults: DirectoryDateDefaults::default(),
+            date_sources_by_extension: BTreeMap::new(),
         }
     }
 
+    /// Find the clock correction for the camera with the given details, if there is one.
+    pub fn camera_offset(
+        &self,
+        make: Option<&str>,
+        model: Option<&str>,
+        serial: Option<&str>,
+    ) -> Option<ConfigDuration> {
+        self.camera_offsets
+            .iter()
+            .find(|camera_offset| camera_offset.matches(make, model, serial))
+            .map(|camera_offset| camera_offset.offset)
+    }
+
+    /// The date sources to use for a file, in order of preference, taking into account any override for its
+    /// extension.
+    pub fn date_sources_for(&self, path: &Path) -> &[DateSource] {
+        path.extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(|extension| {
+                self.date_sources_by_extension
+                    .iter()
+                    .find(|(key, _)| key.eq_ignore_ascii_case(extension))
+            })
+            .map(|(_, date_sources)| date_sources.as_slice())
+            .unwrap_or(&self.date_sources)
+    }
+
+    /// Whether a date falls within the plausible range, inclusive of the days at either end.
+    pub fn is_plausible(&self, date: &CaptureTime) -> bool {
+        let max_date = self
+            .max_date
+            .unwrap_or_else(|| Local::now().date_naive() + Duration::days(1));
+
+        (self.min_date..=max_date).contains(&date.local.date())
+    }
+
     pub fn read_or_create() -> Result<Option<Self>, std::io::Error> {
         let mut config_file = match File::open(CONFIG_FILENAME) {
             Ok(file) => file,
@@ -48,7 +295,7 @@ impl RenamerConfig {
                 let serialised = toml::to_string(&default_config).unwrap();
 
                 let mut config_file = File::create(CONFIG_FILENAME)?;
-                config_file.write(&serialised.as_bytes())?;
+                config_file.write_all(serialised.as_bytes())?;
 
                 println!("New config file {} created, please edit settings and re-run to begin renaming.", CONFIG_FILENAME);
 
@@ -0,0 +1,389 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Error};
+use log::info;
+use rusqlite::{Connection, OpenFlags};
+
+/// A single step in the evolution of the DB schema. Migrations are applied in order, and each one bumps the
+/// DB's `user_version` by one, so that version N has had the first N migrations applied.
+struct Migration {
+    description: &'static str,
+    apply: fn(&Connection) -> rusqlite::Result<()>,
+}
+
+/// Every migration, in the order they must be applied. Only ever add to the end of this list.
+///
+/// DBs from before versioning was introduced have a `user_version` of 0, but may already have some of these
+/// changes, so early migrations check before altering anything.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "create files table",
+        apply: |db| {
+            db.execute_batch(
+                "CREATE TABLE IF NOT EXISTS files (filename TEXT, checksum TEXT);
+                 CREATE UNIQUE INDEX IF NOT EXISTS unique_paths ON files (filename);",
+            )
+        },
+    },
+    Migration {
+        description: "record transfer modes",
+        apply: |db| {
+            // Older DBs pre-date transfer modes, at which point everything was copied
+            if add_column_if_missing(db, "files", "transfer_mode", "TEXT")? {
+                db.execute("UPDATE files SET transfer_mode = 'copy'", ())?;
+            }
+            Ok(())
+        },
+    },
+    Migration {
+        description: "record output paths",
+        apply: |db| add_column_if_missing(db, "files", "destination", "TEXT").map(|_| ()),
+    },
+    Migration {
+        description: "create operations journal",
+        apply: |db| {
+            db.execute(
+                "CREATE TABLE IF NOT EXISTS operations (run_id TEXT, source TEXT, destination TEXT, operation TEXT)",
+                (),
+            )
+            .map(|_| ())
+        },
+    },
+    Migration {
+        description: "record run ids, date sources, sizes and modified times",
+        apply: |db| {
+            db.execute_batch(
+                "ALTER TABLE files ADD COLUMN run_id TEXT;
+                 ALTER TABLE files ADD COLUMN date_source TEXT;
+                 ALTER TABLE files ADD COLUMN size INTEGER;
+                 ALTER TABLE files ADD COLUMN mtime INTEGER;",
+            )
+        },
+    },
+    Migration {
+        description: "record resolved dates and conflicting candidates",
+        apply: |db| {
+            db.execute_batch(
+                "ALTER TABLE files ADD COLUMN date TEXT;
+                 ALTER TABLE files ADD COLUMN date_candidates TEXT;",
+            )
+        },
+    },
+    Migration {
+        description: "record the primary file of sidecars",
+        apply: |db| {
+            db.execute("ALTER TABLE files ADD COLUMN parent TEXT", ())
+                .map(|_| ())
+        },
+    },
+    Migration {
+        description: "record checksums of outputs which differ from their source",
+        apply: |db| {
+            db.execute("ALTER TABLE files ADD COLUMN destination_checksum TEXT", ())
+                .map(|_| ())
+        },
+    },
+    Migration {
+        description: "record Live Photo pairs",
+        apply: |db| {
+            db.execute("ALTER TABLE files ADD COLUMN live_photo TEXT", ())
+                .map(|_| ())
+        },
+    },
+    Migration {
+        description: "index checksums for duplicate detection",
+        apply: |db| {
+            db.execute(
+                "CREATE INDEX IF NOT EXISTS file_checksums ON files (checksum)",
+                (),
+            )
+            .map(|_| ())
+        },
+    },
+    Migration {
+        description: "checksum files recorded with their path in place of a checksum",
+        apply: checksum_legacy_files,
+    },
+];
+
+/// The earliest versions recorded each file's path in the checksum column, so files recorded then would never
+/// be spotted as duplicates. Hash any of those that are still where they were found. The rest are left as they
+/// are, as their path still can't match a real checksum.
+fn checksum_legacy_files(db: &Connection) -> rusqlite::Result<()> {
+    let filenames = db
+        .prepare("SELECT filename FROM files WHERE checksum = filename")?
+        .query_map((), |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+
+    if filenames.is_empty() {
+        return Ok(());
+    }
+
+    info!(
+        "Calculating checksums for {} files recorded without one",
+        filenames.len()
+    );
+
+    let mut update_statement = db.prepare("UPDATE files SET checksum = ? WHERE filename = ?")?;
+
+    for filename in filenames {
+        if let Ok(checksum) = file_checksum(Path::new(&filename)) {
+            update_statement.execute(rusqlite::params![checksum, filename])?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Add a column to a table unless it's already there, returning whether it was added.
+fn add_column_if_missing(
+    db: &Connection,
+    table: &str,
+    column: &str,
+    column_type: &str,
+) -> rusqlite::Result<bool> {
+    let exists = db
+        .prepare(&format!(
+            "SELECT * FROM pragma_table_info('{}') WHERE name = ?",
+            table
+        ))?
+        .exists(rusqlite::params![column])?;
+
+    if !exists {
+        db.execute(
+            &format!(
+                "ALTER TABLE {} ADD COLUMN {} {}",
+                table, column, column_type
+            ),
+            (),
+        )?;
+    }
+
+    Ok(!exists)
+}
+
+/// Calculate a BLAKE3 checksum of a file's contents, returned as a hex string, as recorded for every file.
+pub fn file_checksum(path: &Path) -> std::io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    let mut input_file = fs::File::open(path)?;
+
+    std::io::copy(&mut input_file, &mut hasher)?;
+
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// The schema version that the running code expects.
+pub fn latest_version() -> u32 {
+    MIGRATIONS.len() as u32
+}
+
+fn get_version(db: &Connection) -> Result<u32, Error> {
+    Ok(db.query_row("PRAGMA user_version", (), |row| row.get(0))?)
+}
+
+/// Where the backup of a DB at the given version is written, alongside the DB itself.
+fn backup_path(db_path: &Path, version: u32) -> PathBuf {
+    let mut backup_name = db_path.as_os_str().to_owned();
+    backup_name.push(format!(".v{}.bak", version));
+
+    PathBuf::from(backup_name)
+}
+
+/// Open the DB at the given path, creating it if needed, and bring its schema up to date. Existing DBs are
+/// backed up once before any migrations are applied, as `<name>.v<version>.bak`.
+pub fn open(db_path: &Path) -> Result<Connection, Error> {
+    let exists = db_path.exists();
+
+    let db_connection = Connection::open(db_path)?;
+
+    let current_version = get_version(&db_connection)?;
+
+    if current_version > latest_version() {
+        return Err(anyhow!(
+            "{} has schema version {}, but this version of renamer only supports up to {}",
+            db_path.to_str().unwrap(),
+            current_version,
+            latest_version()
+        ));
+    }
+
+    if exists && current_version < latest_version() {
+        let backup = backup_path(db_path, current_version);
+
+        if backup.exists() {
+            fs::remove_file(&backup)?;
+        }
+
+        db_connection.execute("VACUUM INTO ?", rusqlite::params![backup.to_str().unwrap()])?;
+
+        info!(
+            "Migrating {} from version {} to {}, backup written to {}",
+            db_path.to_str().unwrap(),
+            current_version,
+            latest_version(),
+            backup.to_str().unwrap()
+        );
+    }
+
+    for (version, migration) in MIGRATIONS
+        .iter()
+        .enumerate()
+        .skip(current_version as usize)
+        .map(|(index, migration)| (index as u32, migration))
+    {
+        if exists {
+            info!(
+                "Applying migration {} ({})",
+                version + 1,
+                migration.description
+            );
+        }
+
+        let transaction = db_connection.unchecked_transaction()?;
+        (migration.apply)(&transaction)?;
+        transaction.pragma_update(None, "user_version", version + 1)?;
+        transaction.commit()?;
+    }
+
+    Ok(db_connection)
+}
+
+/// Open the DB at the given path for reading only, without creating or migrating it. Returns None if there's
+/// no DB yet, and an error if its schema isn't the latest, as queries may rely on anything up to that.
+pub fn open_existing(db_path: &Path) -> Result<Option<Connection>, Error> {
+    if !db_path.exists() {
+        return Ok(None);
+    }
+
+    let db_connection = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+    let current_version = get_version(&db_connection)?;
+
+    if current_version != latest_version() {
+        return Err(anyhow!(
+            "{} has schema version {}, but this version of renamer expects {}",
+            db_path.to_str().unwrap(),
+            current_version,
+            latest_version()
+        ));
+    }
+
+    Ok(Some(db_connection))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{fixture_directory, write_fixture};
+
+    /// A path for a DB of its own, with any DB or backups left from an earlier run removed.
+    fn db_path(name: &str) -> PathBuf {
+        let db_path = fixture_directory().join(name);
+
+        for version in 0..=latest_version() {
+            let _ = fs::remove_file(backup_path(&db_path, version));
+        }
+        let _ = fs::remove_file(&db_path);
+
+        db_path
+    }
+
+    fn backups(db_path: &Path) -> Vec<u32> {
+        (0..=latest_version())
+            .filter(|version| backup_path(db_path, *version).exists())
+            .collect()
+    }
+
+    #[test]
+    fn creates_new_dbs_at_the_latest_version() {
+        let db_path = db_path("new.db");
+
+        let db_connection = open(&db_path).unwrap();
+
+        assert_eq!(get_version(&db_connection).unwrap(), latest_version());
+        assert_eq!(backups(&db_path), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn migrates_unversioned_dbs_with_one_backup() {
+        let db_path = db_path("v0.db");
+
+        // The earliest versions recorded each file's path in place of its checksum
+        let source = write_fixture("IMG_0002.JPG", b"legacy");
+        let source = source.to_str().unwrap();
+
+        {
+            let db_connection = Connection::open(&db_path).unwrap();
+            db_connection
+                .execute_batch(
+                    "CREATE TABLE files (filename TEXT, checksum TEXT);
+                     CREATE UNIQUE INDEX unique_paths ON files (filename);
+                     INSERT INTO files VALUES ('/photos/IMG_0001.JPG', '/photos/IMG_0001.JPG');",
+                )
+                .unwrap();
+            db_connection
+                .execute(
+                    "INSERT INTO files VALUES (?1, ?1)",
+                    rusqlite::params![source],
+                )
+                .unwrap();
+        }
+
+        let db_connection = open(&db_path).unwrap();
+
+        assert_eq!(get_version(&db_connection).unwrap(), latest_version());
+        assert_eq!(backups(&db_path), vec![0]);
+
+        let (transfer_mode, destination): (String, Option<String>) = db_connection
+            .query_row(
+                "SELECT transfer_mode, destination FROM files WHERE filename = '/photos/IMG_0001.JPG'",
+                (),
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+
+        assert_eq!(transfer_mode, "copy");
+        assert_eq!(destination, None);
+
+        // Files which are still around are given a real checksum, and the rest are left alone
+        let checksum = |filename: &str| -> String {
+            db_connection
+                .query_row(
+                    "SELECT checksum FROM files WHERE filename = ?",
+                    rusqlite::params![filename],
+                    |row| row.get(0),
+                )
+                .unwrap()
+        };
+
+        assert_eq!(
+            checksum(source),
+            blake3::hash(b"legacy").to_hex().to_string()
+        );
+        assert_eq!(checksum("/photos/IMG_0001.JPG"), "/photos/IMG_0001.JPG");
+
+        // The backup is of the DB as it was before migrating
+        let backup = Connection::open(backup_path(&db_path, 0)).unwrap();
+        assert_eq!(get_version(&backup).unwrap(), 0);
+
+        // Opening an up to date DB leaves it alone
+        drop(db_connection);
+        fs::remove_file(backup_path(&db_path, 0)).unwrap();
+        open(&db_path).unwrap();
+        assert_eq!(backups(&db_path), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn refuses_dbs_from_newer_versions() {
+        let db_path = db_path("future.db");
+
+        Connection::open(&db_path)
+            .unwrap()
+            .pragma_update(None, "user_version", latest_version() + 1)
+            .unwrap();
+
+        assert!(open(&db_path).is_err());
+        assert!(open_existing(&db_path).is_err());
+    }
+}
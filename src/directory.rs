@@ -0,0 +1,336 @@
+use std::path::Path;
+
+use chrono::{Datelike, NaiveDate, NaiveTime};
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::timestamp::CaptureTime;
+
+/// A rule for finding a date in a directory name, as written in the config file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct DirectoryPatternConfig {
+    name: String,
+    pattern: String,
+}
+
+/// A rule for finding a date in a directory name, using a regex with named groups `year`, and optionally
+/// `month` and `day`. Any parts without a group, or which don't match, are filled in from the defaults.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "DirectoryPatternConfig", into = "DirectoryPatternConfig")]
+pub struct DirectoryPattern {
+    /// Short name for the rule, shown when explaining where a date came from
+    pub name: String,
+    regex: Regex,
+}
+
+impl DirectoryPattern {
+    pub fn new(name: &str, pattern: &str) -> Result<Self, String> {
+        let regex = Regex::new(pattern)
+            .map_err(|e| format!("invalid pattern for directory rule '{}': {}", name, e))?;
+
+        if !regex.capture_names().any(|group| group == Some("year")) {
+            return Err(format!(
+                "pattern for directory rule '{}' must have a named group for the year, (?P<year>...)",
+                name
+            ));
+        }
+
+        Ok(DirectoryPattern {
+            name: name.to_string(),
+            regex,
+        })
+    }
+
+    /// Try to read a date from a single directory name using this rule.
+    pub fn extract(&self, name: &str, defaults: &DirectoryDateDefaults) -> Option<CaptureTime> {
+        let captures = self.regex.captures(name)?;
+
+        let part = |group: &str| -> Option<u32> { captures.name(group)?.as_str().parse().ok() };
+
+        let year = part("year")? as i32;
+        let month = part("month").unwrap_or(defaults.month);
+
+        // Clamp the default day to the length of the month, so that a default of 31 means the last day
+        let day = match part("day") {
+            Some(day) => day,
+            None => defaults.day.min(days_in_month(year, month)?),
+        };
+
+        let date = NaiveDate::from_ymd_opt(year, month, day)?;
+
+        Some(CaptureTime::naive(date.and_time(defaults.time)))
+    }
+}
+
+fn days_in_month(year: i32, month: u32) -> Option<u32> {
+    let first_of_next_month = match month {
+        12 => NaiveDate::from_ymd_opt(year + 1, 1, 1)?,
+        _ => NaiveDate::from_ymd_opt(year, month + 1, 1)?,
+    };
+
+    Some(first_of_next_month.pred_opt()?.day())
+}
+
+impl TryFrom<DirectoryPatternConfig> for DirectoryPattern {
+    type Error = String;
+
+    fn try_from(value: DirectoryPatternConfig) -> Result<Self, Self::Error> {
+        DirectoryPattern::new(&value.name, &value.pattern)
+    }
+}
+
+impl From<DirectoryPattern> for DirectoryPatternConfig {
+    fn from(value: DirectoryPattern) -> Self {
+        DirectoryPatternConfig {
+            name: value.name,
+            pattern: value.regex.as_str().to_string(),
+        }
+    }
+}
+
+/// Defaults for the parts of a date missing from a directory name, as written in the config file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+struct DirectoryDateDefaultsConfig {
+    month: u32,
+    day: u32,
+    time: String,
+}
+
+impl Default for DirectoryDateDefaultsConfig {
+    fn default() -> Self {
+        DirectoryDateDefaults::default().into()
+    }
+}
+
+/// What to use for the parts of a date missing from a directory name, such as the month and day for a
+/// directory named after just the year.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(
+    try_from = "DirectoryDateDefaultsConfig",
+    into = "DirectoryDateDefaultsConfig"
+)]
+pub struct DirectoryDateDefaults {
+    pub month: u32,
+    /// Day of the month, limited to the length of the month
+    pub day: u32,
+    pub time: NaiveTime,
+}
+
+impl Default for DirectoryDateDefaults {
+    fn default() -> Self {
+        DirectoryDateDefaults {
+            month: 1,
+            day: 1,
+            time: NaiveTime::MIN,
+        }
+    }
+}
+
+impl TryFrom<DirectoryDateDefaultsConfig> for DirectoryDateDefaults {
+    type Error = String;
+
+    fn try_from(value: DirectoryDateDefaultsConfig) -> Result<Self, Self::Error> {
+        if !(1..=12).contains(&value.month) || !(1..=31).contains(&value.day) {
+            return Err(format!(
+                "invalid directory date defaults, month {} and day {}",
+                value.month, value.day
+            ));
+        }
+
+        let time = NaiveTime::parse_from_str(&value.time, "%H:%M:%S").map_err(|_| {
+            format!(
+                "invalid directory date default time '{}', expected HH:MM:SS",
+                value.time
+            )
+        })?;
+
+        Ok(DirectoryDateDefaults {
+            month: value.month,
+            day: value.day,
+            time,
+        })
+    }
+}
+
+impl From<DirectoryDateDefaults> for DirectoryDateDefaultsConfig {
+    fn from(value: DirectoryDateDefaults) -> Self {
+        DirectoryDateDefaultsConfig {
+            month: value.month,
+            day: value.day,
+            time: value.time.format("%H:%M:%S").to_string(),
+        }
+    }
+}
+
+/// The rules used when none are specified in the config, most specific first.
+pub fn default_directory_patterns() -> Vec<DirectoryPattern> {
+    [
+        // e.g. 2009-07-15 Birthday or 20090715
+        (
+            "date",
+            r"(?:^|\D)(?P<year>(?:19|20)\d{2})[-_. ]?(?P<month>0[1-9]|1[0-2])[-_. ]?(?P<day>0[1-9]|[12]\d|3[01])(?:\D|$)",
+        ),
+        // e.g. 2009-07 Holiday
+        (
+            "year_month",
+            r"(?:^|\D)(?P<year>(?:19|20)\d{2})[-_. ](?P<month>0[1-9]|1[0-2])(?:\D|$)",
+        ),
+        // e.g. 2009, or 2009 Scans
+        ("year", r"^(?P<year>(?:19|20)\d{2})(?:\D|$)"),
+    ]
+    .iter()
+    .map(|(name, pattern)| DirectoryPattern::new(name, pattern).unwrap())
+    .collect()
+}
+
+/// Find a date in the names of a file's ancestor directories, nearest first, as the nearest is likely to be
+/// the most specific. Only directories up to and including the root the file was found under are used, as
+/// anything above it says nothing about the files inside. Returns the date along with the rule and directory
+/// name that matched.
+pub fn extract_date<'a>(
+    file: &'a Path,
+    root: Option<&Path>,
+    patterns: &'a [DirectoryPattern],
+    defaults: &DirectoryDateDefaults,
+) -> Option<(CaptureTime, &'a DirectoryPattern, &'a str)> {
+    file.ancestors()
+        .skip(1)
+        .take_while(|directory| root.is_none_or(|root| directory.starts_with(root)))
+        .filter_map(|directory| directory.file_name()?.to_str())
+        .find_map(|name| {
+            patterns.iter().find_map(|pattern| {
+                pattern
+                    .extract(name, defaults)
+                    .map(|date| (date, pattern, name))
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32, time: NaiveTime) -> CaptureTime {
+        CaptureTime::naive(
+            NaiveDate::from_ymd_opt(year, month, day)
+                .unwrap()
+                .and_time(time),
+        )
+    }
+
+    fn extract(
+        file: &str,
+        root: Option<&str>,
+        defaults: &DirectoryDateDefaults,
+    ) -> Option<(CaptureTime, String)> {
+        let patterns = default_directory_patterns();
+
+        extract_date(Path::new(file), root.map(Path::new), &patterns, defaults)
+            .map(|(date, pattern, _)| (date, pattern.name.clone()))
+    }
+
+    #[test]
+    fn default_patterns_read_common_directory_names() {
+        let defaults = DirectoryDateDefaults::default();
+        let cases = [
+            (
+                "photos/2009-07-15 Birthday/IMG_0001.JPG",
+                date(2009, 7, 15, NaiveTime::MIN),
+                "date",
+            ),
+            (
+                "photos/20090715/IMG_0001.JPG",
+                date(2009, 7, 15, NaiveTime::MIN),
+                "date",
+            ),
+            (
+                "photos/2009/2009-07 Holiday/IMG_0001.JPG",
+                date(2009, 7, 1, NaiveTime::MIN),
+                "year_month",
+            ),
+            (
+                "photos/2009 Scans/IMG_0001.JPG",
+                date(2009, 1, 1, NaiveTime::MIN),
+                "year",
+            ),
+        ];
+
+        for (file, expected, name) in cases {
+            assert_eq!(
+                extract(file, None, &defaults),
+                Some((expected, name.to_string())),
+                "{}",
+                file
+            );
+        }
+
+        assert_eq!(
+            extract("photos/Holiday 1234/IMG_0001.JPG", None, &defaults),
+            None
+        );
+
+        // An impossible month falls back to the year alone
+        assert_eq!(
+            extract("photos/2009-13 Holiday/IMG_0001.JPG", None, &defaults),
+            Some((date(2009, 1, 1, NaiveTime::MIN), String::from("year")))
+        );
+    }
+
+    #[test]
+    fn stops_at_the_root_path() {
+        let defaults = DirectoryDateDefaults::default();
+
+        assert_eq!(
+            extract("2009/photos/IMG_0001.JPG", Some("2009/photos"), &defaults),
+            None
+        );
+        assert_eq!(
+            extract("2009/photos/IMG_0001.JPG", Some("2009"), &defaults),
+            Some((date(2009, 1, 1, NaiveTime::MIN), String::from("year")))
+        );
+    }
+
+    #[test]
+    fn fills_in_missing_parts_from_defaults() {
+        let noon = NaiveTime::from_hms_opt(12, 0, 0).unwrap();
+        let defaults = DirectoryDateDefaults {
+            month: 2,
+            day: 31,
+            time: noon,
+        };
+
+        // The default day is limited to the length of the month, including in leap years
+        assert_eq!(
+            extract("photos/2009/IMG_0001.JPG", None, &defaults),
+            Some((date(2009, 2, 28, noon), String::from("year")))
+        );
+        assert_eq!(
+            extract("photos/2008/IMG_0001.JPG", None, &defaults),
+            Some((date(2008, 2, 29, noon), String::from("year")))
+        );
+        assert_eq!(
+            extract("photos/2009-12 Christmas/IMG_0001.JPG", None, &defaults),
+            Some((date(2009, 12, 31, noon), String::from("year_month")))
+        );
+    }
+
+    #[test]
+    fn validates_config() {
+        let defaults = |month, day, time: &str| {
+            DirectoryDateDefaults::try_from(DirectoryDateDefaultsConfig {
+                month,
+                day,
+                time: time.to_string(),
+            })
+        };
+
+        assert!(defaults(6, 15, "12:00:00").is_ok());
+        assert!(defaults(13, 1, "00:00:00").is_err());
+        assert!(defaults(1, 0, "00:00:00").is_err());
+        assert!(defaults(1, 1, "noon").is_err());
+
+        assert!(DirectoryPattern::new("no_year", r"(?P<month>\d{2})").is_err());
+        assert!(DirectoryPattern::new("bad_regex", r"(?P<year>\d{4}").is_err());
+    }
+}
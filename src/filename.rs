@@ -0,0 +1,252 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::timestamp::CaptureTime;
+
+/// Format for names holding the number of seconds since the Unix epoch.
+const EPOCH_SECONDS_FORMAT: &str = "epoch_seconds";
+
+/// Format for names holding the number of milliseconds since the Unix epoch.
+const EPOCH_MILLIS_FORMAT: &str = "epoch_millis";
+
+/// A rule for finding a date in a filename, as written in the config file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct FilenamePatternConfig {
+    name: String,
+    pattern: String,
+    format: String,
+    #[serde(default)]
+    utc: bool,
+}
+
+/// A rule for finding a date in a filename. The text captured by the pattern's groups is joined together, or
+/// the whole match used if it has none, and parsed with a chrono format string. Formats without a time give
+/// midnight on that date. The special formats `epoch_seconds` and `epoch_millis` read a Unix timestamp.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "FilenamePatternConfig", into = "FilenamePatternConfig")]
+pub struct FilenamePattern {
+    /// Short name for the rule, shown when explaining where a date came from
+    pub name: String,
+    regex: Regex,
+    format: String,
+    /// Whether the date in the name is UTC rather than local time
+    utc: bool,
+}
+
+impl FilenamePattern {
+    pub fn new(name: &str, pattern: &str, format: &str, utc: bool) -> Result<Self, String> {
+        let regex = Regex::new(pattern)
+            .map_err(|e| format!("invalid pattern for filename rule '{}': {}", name, e))?;
+
+        let is_epoch = format == EPOCH_SECONDS_FORMAT || format == EPOCH_MILLIS_FORMAT;
+
+        if !is_epoch && StrftimeItems::new(format).any(|item| item == Item::Error) {
+            return Err(format!(
+                "invalid date format '{}' for filename rule '{}'",
+                format, name
+            ));
+        }
+
+        Ok(FilenamePattern {
+            name: name.to_string(),
+            regex,
+            format: format.to_string(),
+            utc,
+        })
+    }
+
+    /// Try to read a date from a filename using this rule.
+    pub fn extract(&self, filename: &str) -> Option<CaptureTime> {
+        let captures = self.regex.captures(filename)?;
+
+        let text = match captures.len() {
+            1 => captures.get(0)?.as_str().to_string(),
+            _ => captures
+                .iter()
+                .skip(1)
+                .flatten()
+                .map(|capture| capture.as_str())
+                .collect::<String>(),
+        };
+
+        let utc_time = match self.format.as_str() {
+            EPOCH_SECONDS_FORMAT => {
+                return Some(CaptureTime::from_utc(
+                    DateTime::from_timestamp(text.parse().ok()?, 0)?.naive_utc(),
+                ));
+            }
+            EPOCH_MILLIS_FORMAT => {
+                return Some(CaptureTime::from_utc(
+                    DateTime::from_timestamp_millis(text.parse().ok()?)?.naive_utc(),
+                ));
+            }
+            format => NaiveDateTime::parse_from_str(&text, format)
+                .ok()
+                .or_else(|| {
+                    NaiveDate::parse_from_str(&text, format)
+                        .ok()?
+                        .and_hms_opt(0, 0, 0)
+                })?,
+        };
+
+        Some(match self.utc {
+            true => CaptureTime::from_utc(utc_time),
+            false => CaptureTime::naive(utc_time),
+        })
+    }
+}
+
+impl TryFrom<FilenamePatternConfig> for FilenamePattern {
+    type Error = String;
+
+    fn try_from(value: FilenamePatternConfig) -> Result<Self, Self::Error> {
+        FilenamePattern::new(&value.name, &value.pattern, &value.format, value.utc)
+    }
+}
+
+impl From<FilenamePattern> for FilenamePatternConfig {
+    fn from(value: FilenamePattern) -> Self {
+        FilenamePatternConfig {
+            name: value.name,
+            pattern: value.regex.as_str().to_string(),
+            format: value.format,
+            utc: value.utc,
+        }
+    }
+}
+
+/// The rules used when none are specified in the config, most specific first, as later rules would also
+/// match some of the names handled by earlier ones. In particular, the general date and time rule could
+/// misread part of an epoch timestamp.
+pub fn default_filename_patterns() -> Vec<FilenamePattern> {
+    [
+        // Google Pixel, e.g. PXL_20200101_101010123, which is in UTC
+        (
+            "pixel",
+            r"PXL_(\d{8})_(\d{6})(\d{3})",
+            "%Y%m%d%H%M%S%3f",
+            true,
+        ),
+        // WhatsApp, e.g. IMG-20200101-WA0001, which only has the date
+        ("whatsapp", r"(?:IMG|VID)-(\d{8})-WA\d+", "%Y%m%d", false),
+        // Samsung screenshots, e.g. Screenshot_20200101-101010_Camera
+        (
+            "samsung_screenshot",
+            r"Screenshot_(\d{8})-(\d{6})",
+            "%Y%m%d%H%M%S",
+            false,
+        ),
+        // Signal and Telegram, e.g. signal-1577873410123, with milliseconds since the epoch
+        (
+            "epoch_millis",
+            r"(?:^|\D)(1\d{12})(?:\D|$)",
+            EPOCH_MILLIS_FORMAT,
+            true,
+        ),
+        // Most cameras and phones, e.g. IMG_20200101_101010 or 20200101-101010
+        ("datetime", r"(\d{8})[-_]?(\d{6})", "%Y%m%d%H%M%S", false),
+    ]
+    .iter()
+    .map(|(name, pattern, format, utc)| FilenamePattern::new(name, pattern, format, *utc).unwrap())
+    .collect()
+}
+
+/// Find a date in a filename using the first rule that matches, returning it along with the rule used.
+pub fn extract_date<'a>(
+    filename: &str,
+    patterns: &'a [FilenamePattern],
+) -> Option<(CaptureTime, &'a FilenamePattern)> {
+    patterns
+        .iter()
+        .find_map(|pattern| pattern.extract(filename).map(|date| (date, pattern)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn naive(date: &str) -> CaptureTime {
+        CaptureTime::naive(NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S%.f").unwrap())
+    }
+
+    fn utc(date: &str) -> CaptureTime {
+        CaptureTime::from_utc(NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S%.f").unwrap())
+    }
+
+    fn extract(filename: &str) -> Option<(CaptureTime, String)> {
+        extract_date(filename, &default_filename_patterns())
+            .map(|(date, pattern)| (date, pattern.name.clone()))
+    }
+
+    #[test]
+    fn default_patterns_read_common_names() {
+        let cases = [
+            (
+                "PXL_20200101_101010123.jpg",
+                utc("2020-01-01 10:10:10.123"),
+                "pixel",
+            ),
+            (
+                "IMG-20200101-WA0001.jpg",
+                naive("2020-01-01 00:00:00"),
+                "whatsapp",
+            ),
+            (
+                "Screenshot_20200101-101010_Camera.png",
+                naive("2020-01-01 10:10:10"),
+                "samsung_screenshot",
+            ),
+            (
+                "signal-1577873410123.jpg",
+                utc("2020-01-01 10:10:10.123"),
+                "epoch_millis",
+            ),
+            (
+                "IMG_20200101_101010.jpg",
+                naive("2020-01-01 10:10:10"),
+                "datetime",
+            ),
+            (
+                "20200101-101010.mp4",
+                naive("2020-01-01 10:10:10"),
+                "datetime",
+            ),
+        ];
+
+        for (filename, date, name) in cases {
+            assert_eq!(
+                extract(filename),
+                Some((date, name.to_string())),
+                "{}",
+                filename
+            );
+        }
+    }
+
+    #[test]
+    fn default_patterns_ignore_other_names() {
+        assert_eq!(extract("IMG_0001.JPG"), None);
+        assert_eq!(extract("IMG_20201301_101010.jpg"), None);
+    }
+
+    #[test]
+    fn custom_patterns() {
+        let whole_match =
+            FilenamePattern::new("whole", r"\d{4}-\d{2}-\d{2}", "%Y-%m-%d", false).unwrap();
+        assert_eq!(
+            whole_match.extract("holiday 2019-07-04.jpg"),
+            Some(naive("2019-07-04 00:00:00"))
+        );
+
+        let epoch = FilenamePattern::new("epoch", r"(\d{10})", "epoch_seconds", true).unwrap();
+        assert_eq!(
+            epoch.extract("1577873410.jpg"),
+            Some(utc("2020-01-01 10:10:10"))
+        );
+
+        assert!(FilenamePattern::new("bad_regex", r"(\d{8}", "%Y%m%d", false).is_err());
+        assert!(FilenamePattern::new("bad_format", r"(\d{8})", "%Y%Q", false).is_err());
+    }
+}
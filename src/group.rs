@@ -0,0 +1,47 @@
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+use serde_derive::{Deserialize, Serialize};
+
+/// How files are gathered into groups that share a date, such as a RAW and the JPEG taken alongside it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupingRule {
+    /// Files with the same stem in the same directory
+    #[default]
+    Directory,
+    /// Files with the same stem anywhere under the root paths, for cameras that write RAWs and JPEGs to
+    /// separate directories. Unrelated files with the same name, such as IMG_0001.jpg from two cameras, will
+    /// be grouped together too.
+    Stem,
+}
+
+/// Identifies the group a file belongs to under a grouping rule.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct GroupKey {
+    /// The directory holding the group, or `None` if the rule groups across directories
+    pub directory: Option<PathBuf>,
+    pub stem: String,
+}
+
+impl GroupKey {
+    pub fn new(path: &Path, rule: GroupingRule) -> Option<Self> {
+        let stem = path.file_stem()?.to_str()?.to_string();
+
+        let directory = match rule {
+            GroupingRule::Directory => Some(path.parent()?.to_path_buf()),
+            GroupingRule::Stem => None,
+        };
+
+        Some(GroupKey { directory, stem })
+    }
+}
+
+impl Display for GroupKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.directory {
+            Some(directory) => write!(f, "{}", directory.join(&self.stem).to_str().unwrap()),
+            None => write!(f, "{}", self.stem),
+        }
+    }
+}
@@ -0,0 +1,417 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::{anyhow, Error};
+use exif::{Exif, Reader};
+
+use crate::isobmff::{child_boxes, find_child, read_box_header};
+
+/// Don't try to load absurdly large `meta` boxes or Exif items into memory, as they're almost certainly
+/// corrupt.
+const MAX_METADATA_SIZE: u64 = 16 * 1024 * 1024;
+
+/// A contiguous run of bytes making up part of an item.
+struct Extent {
+    offset: u64,
+    length: u64,
+}
+
+/// Where an item's data lives, as described by an `iloc` entry.
+struct ItemLocation {
+    /// 0 for offsets within the file, 1 for offsets within the `idat` box
+    construction_method: u16,
+    extents: Vec<Extent>,
+}
+
+/// A simple cursor for reading big-endian values out of a box's contents.
+struct BoxReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> BoxReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BoxReader { data, offset: 0 }
+    }
+
+    fn skip(&mut self, count: usize) -> Option<()> {
+        self.bytes(count).map(|_| ())
+    }
+
+    fn bytes(&mut self, count: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.offset..self.offset + count)?;
+        self.offset += count;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    /// Read an unsigned value of 0, 4 or 8 bytes, as used by the variable-sized fields in `iloc`.
+    fn sized(&mut self, size: u8) -> Option<u64> {
+        match size {
+            0 => Some(0),
+            4 => Some(self.u32()? as u64),
+            8 => Some(u64::from_be_bytes(self.bytes(8)?.try_into().ok()?)),
+            _ => None,
+        }
+    }
+
+    fn remaining(&self) -> &'a [u8] {
+        &self.data[self.offset.min(self.data.len())..]
+    }
+}
+
+/// Find the ID of the item with type `Exif` in an `iinf` box.
+fn find_exif_item_id(iinf: &[u8]) -> Option<u32> {
+    let mut reader = BoxReader::new(iinf);
+    let version = reader.u8()?;
+    reader.skip(3)?;
+
+    match version {
+        0 => reader.skip(2)?,
+        _ => reader.skip(4)?,
+    };
+
+    for (box_type, infe) in child_boxes(reader.remaining()) {
+        if &box_type != b"infe" {
+            continue;
+        }
+
+        let mut infe_reader = BoxReader::new(infe);
+        let infe_version = infe_reader.u8()?;
+        infe_reader.skip(3)?;
+
+        // Item types were only added in version 2 of the item info entry
+        let item_id = match infe_version {
+            2 => infe_reader.u16()? as u32,
+            3 => infe_reader.u32()?,
+            _ => continue,
+        };
+
+        infe_reader.skip(2)?;
+
+        if infe_reader.bytes(4)? == b"Exif" {
+            return Some(item_id);
+        }
+    }
+
+    None
+}
+
+/// Find the location of the given item in an `iloc` box.
+fn find_item_location(iloc: &[u8], wanted_item_id: u32) -> Option<ItemLocation> {
+    let mut reader = BoxReader::new(iloc);
+    let version = reader.u8()?;
+    reader.skip(3)?;
+
+    let sizes = reader.u8()?;
+    let (offset_size, length_size) = (sizes >> 4, sizes & 0x0f);
+    let sizes = reader.u8()?;
+    let base_offset_size = sizes >> 4;
+    let index_size = match version {
+        1 | 2 => sizes & 0x0f,
+        _ => 0,
+    };
+
+    let item_count = match version {
+        2 => reader.u32()?,
+        _ => reader.u16()? as u32,
+    };
+
+    for _ in 0..item_count {
+        let item_id = match version {
+            2 => reader.u32()?,
+            _ => reader.u16()? as u32,
+        };
+
+        let construction_method = match version {
+            1 | 2 => reader.u16()? & 0x0f,
+            _ => 0,
+        };
+
+        reader.skip(2)?;
+        let base_offset = reader.sized(base_offset_size)?;
+        let extent_count = reader.u16()?;
+        let mut extents = vec![];
+
+        for _ in 0..extent_count {
+            reader.sized(index_size)?;
+            let offset = reader.sized(offset_size)?;
+            let length = reader.sized(length_size)?;
+
+            extents.push(Extent {
+                offset: base_offset.checked_add(offset)?,
+                length,
+            });
+        }
+
+        if item_id == wanted_item_id {
+            return Some(ItemLocation {
+                construction_method,
+                extents,
+            });
+        }
+    }
+
+    None
+}
+
+/// Read the EXIF data from a HEIF container, such as a HEIC or AVIF image. The `meta` box lists the items in
+/// the file in `iinf`, and where each one's data is stored in `iloc`, so use those to find the `Exif` item.
+pub fn read_exif(path: &Path) -> Result<Exif, Error> {
+    let input_file = File::open(path)?;
+    let file_size = input_file.metadata()?.len();
+    let mut reader = BufReader::new(input_file);
+
+    let mut meta = None;
+
+    while let Some(header) = read_box_header(&mut reader, file_size)? {
+        if &header.box_type == b"meta" {
+            if header.content_size > MAX_METADATA_SIZE {
+                return Err(anyhow!("meta box too large in {:?}", path));
+            }
+
+            let mut contents = vec![0u8; header.content_size as usize];
+            reader.read_exact(&mut contents)?;
+            meta = Some(contents);
+            break;
+        }
+
+        reader.seek(SeekFrom::Start(header.content_offset + header.content_size))?;
+    }
+
+    let meta = meta.ok_or_else(|| anyhow!("No meta box found in {:?}", path))?;
+
+    // The meta box is a full box, so skip the version and flags
+    let meta_children = meta
+        .get(4..)
+        .ok_or_else(|| anyhow!("Truncated meta box in {:?}", path))?;
+
+    let exif_item_id = find_child(meta_children, b"iinf")
+        .and_then(find_exif_item_id)
+        .ok_or_else(|| anyhow!("No Exif item found in {:?}", path))?;
+
+    let location = find_child(meta_children, b"iloc")
+        .and_then(|iloc| find_item_location(iloc, exif_item_id))
+        .ok_or_else(|| anyhow!("No location for Exif item found in {:?}", path))?;
+
+    let mut item_data = vec![];
+
+    for extent in &location.extents {
+        // A zero length means the extent runs to the end of the file
+        let length = match (location.construction_method, extent.length) {
+            (0, 0) => file_size.saturating_sub(extent.offset),
+            (_, length) => length,
+        };
+
+        if (item_data.len() as u64).saturating_add(length) > MAX_METADATA_SIZE {
+            return Err(anyhow!("Exif item too large in {:?}", path));
+        }
+
+        match location.construction_method {
+            0 => {
+                let mut extent_data = vec![0u8; length as usize];
+                reader.seek(SeekFrom::Start(extent.offset))?;
+                reader.read_exact(&mut extent_data)?;
+                item_data.extend(extent_data);
+            }
+            1 => {
+                let idat = find_child(meta_children, b"idat")
+                    .ok_or_else(|| anyhow!("No idat box found in {:?}", path))?;
+                let extent_data = extent
+                    .offset
+                    .checked_add(length)
+                    .and_then(|end| {
+                        idat.get(usize::try_from(extent.offset).ok()?..usize::try_from(end).ok()?)
+                    })
+                    .ok_or_else(|| anyhow!("Invalid idat extent in {:?}", path))?;
+                item_data.extend_from_slice(extent_data);
+            }
+            method => {
+                return Err(anyhow!(
+                    "Unsupported item construction method {} in {:?}",
+                    method,
+                    path
+                ));
+            }
+        }
+    }
+
+    // The Exif item starts with the offset of the TIFF header from the end of the offset field itself
+    let tiff_header_offset = u32::from_be_bytes(
+        item_data
+            .get(0..4)
+            .ok_or_else(|| anyhow!("Truncated Exif item in {:?}", path))?
+            .try_into()?,
+    ) as usize;
+
+    let tiff_data = item_data
+        .get(4 + tiff_header_offset..)
+        .ok_or_else(|| anyhow!("Invalid Exif item in {:?}", path))?
+        .to_vec();
+
+    Ok(Reader::new().read_raw(tiff_data)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use exif::{In, Tag};
+
+    use super::*;
+    use crate::test_util::{ascii_tiff, mp4_box, write_fixture};
+
+    /// Build an `iinf` box's contents listing a single `Exif` item, alongside an image item.
+    fn iinf(exif_item_id: u16) -> Vec<u8> {
+        let infe = |item_id: u16, item_type: &[u8; 4]| {
+            let mut contents = vec![2, 0, 0, 0];
+            contents.extend_from_slice(&item_id.to_be_bytes());
+            contents.extend_from_slice(&[0, 0]);
+            contents.extend_from_slice(item_type);
+            contents.push(0);
+            mp4_box(b"infe", &contents)
+        };
+
+        let mut contents = vec![0, 0, 0, 0, 0, 2];
+        contents.extend(infe(1, b"hvc1"));
+        contents.extend(infe(exif_item_id, b"Exif"));
+        contents
+    }
+
+    #[test]
+    fn finds_exif_item_id() {
+        assert_eq!(find_exif_item_id(&iinf(7)), Some(7));
+        assert_eq!(find_exif_item_id(&iinf(7)[..6]), None);
+    }
+
+    #[test]
+    fn reads_iloc_version_0() {
+        // Four byte offsets and lengths, no base offset, with two items
+        let mut iloc = vec![0, 0, 0, 0, 0x44, 0x00, 0, 2];
+
+        for (item_id, offset, length) in [(1u16, 100u32, 10u32), (2, 200, 20)] {
+            iloc.extend_from_slice(&item_id.to_be_bytes());
+            iloc.extend_from_slice(&[0, 0, 0, 1]);
+            iloc.extend_from_slice(&offset.to_be_bytes());
+            iloc.extend_from_slice(&length.to_be_bytes());
+        }
+
+        let location = find_item_location(&iloc, 2).unwrap();
+        assert_eq!(location.construction_method, 0);
+        assert_eq!(
+            location
+                .extents
+                .iter()
+                .map(|extent| (extent.offset, extent.length))
+                .collect::<Vec<(u64, u64)>>(),
+            vec![(200, 20)]
+        );
+
+        assert!(find_item_location(&iloc, 3).is_none());
+    }
+
+    #[test]
+    fn reads_iloc_version_1_with_base_offset_and_index() {
+        // Four byte offsets, lengths, base offsets and indexes, with two extents
+        let mut iloc = vec![1, 0, 0, 0, 0x44, 0x44, 0, 1, 0, 5, 0, 1, 0, 0];
+        iloc.extend_from_slice(&1000u32.to_be_bytes());
+        iloc.extend_from_slice(&[0, 2]);
+
+        for (offset, length) in [(10u32, 4u32), (20, 6)] {
+            iloc.extend_from_slice(&0u32.to_be_bytes());
+            iloc.extend_from_slice(&offset.to_be_bytes());
+            iloc.extend_from_slice(&length.to_be_bytes());
+        }
+
+        let location = find_item_location(&iloc, 5).unwrap();
+        assert_eq!(location.construction_method, 1);
+        assert_eq!(
+            location
+                .extents
+                .iter()
+                .map(|extent| (extent.offset, extent.length))
+                .collect::<Vec<(u64, u64)>>(),
+            vec![(1010, 4), (1020, 6)]
+        );
+    }
+
+    #[test]
+    fn reads_iloc_version_2() {
+        // Eight byte offsets and lengths, with 32 bit item IDs and counts
+        let mut iloc = vec![2, 0, 0, 0, 0x88, 0x00];
+        iloc.extend_from_slice(&1u32.to_be_bytes());
+        iloc.extend_from_slice(&70000u32.to_be_bytes());
+        iloc.extend_from_slice(&[0, 0, 0, 0, 0, 1]);
+        iloc.extend_from_slice(&5_000_000_000u64.to_be_bytes());
+        iloc.extend_from_slice(&16u64.to_be_bytes());
+
+        let location = find_item_location(&iloc, 70000).unwrap();
+        assert_eq!(location.construction_method, 0);
+        assert_eq!(location.extents[0].offset, 5_000_000_000);
+        assert_eq!(location.extents[0].length, 16);
+    }
+
+    #[test]
+    fn rejects_overflowing_or_truncated_iloc() {
+        let mut iloc = vec![1, 0, 0, 0, 0x88, 0x80, 0, 1, 0, 1, 0, 0, 0, 0];
+        iloc.extend_from_slice(&u64::MAX.to_be_bytes());
+        iloc.extend_from_slice(&[0, 1]);
+        iloc.extend_from_slice(&1u64.to_be_bytes());
+        iloc.extend_from_slice(&1u64.to_be_bytes());
+
+        assert!(find_item_location(&iloc, 1).is_none());
+        assert!(find_item_location(&iloc[..12], 1).is_none());
+    }
+
+    #[test]
+    fn reads_exif_from_idat_extent() {
+        let mut exif_item = 0u32.to_be_bytes().to_vec();
+        exif_item.extend(ascii_tiff(&[(0x0132, "2021:02:03 04:05:06")]));
+
+        // The item is stored in idat after some padding, so its offset is relative to idat
+        let mut idat = b"padding!".to_vec();
+        idat.extend_from_slice(&exif_item);
+
+        let mut iloc = vec![1, 0, 0, 0, 0x44, 0x00, 0, 1, 0, 2, 0, 1, 0, 0, 0, 1];
+        iloc.extend_from_slice(&8u32.to_be_bytes());
+        iloc.extend_from_slice(&(exif_item.len() as u32).to_be_bytes());
+
+        let mut meta = vec![0, 0, 0, 0];
+        meta.extend(mp4_box(b"iinf", &iinf(2)));
+        meta.extend(mp4_box(b"iloc", &iloc));
+        meta.extend(mp4_box(b"idat", &idat));
+
+        let mut data = mp4_box(b"ftyp", b"heic");
+        data.extend(mp4_box(b"meta", &meta));
+
+        let exif_data = read_exif(&write_fixture("idat.heic", &data)).unwrap();
+        let field = exif_data.get_field(Tag::DateTime, In::PRIMARY).unwrap();
+        assert_eq!(field.display_value().to_string(), "2021-02-03 04:05:06");
+    }
+
+    #[test]
+    fn rejects_idat_extent_outside_idat() {
+        let mut iloc = vec![1, 0, 0, 0, 0x44, 0x00, 0, 1, 0, 2, 0, 1, 0, 0, 0, 1];
+        iloc.extend_from_slice(&4u32.to_be_bytes());
+        iloc.extend_from_slice(&u32::MAX.to_be_bytes());
+
+        let mut meta = vec![0, 0, 0, 0];
+        meta.extend(mp4_box(b"iinf", &iinf(2)));
+        meta.extend(mp4_box(b"iloc", &iloc));
+        meta.extend(mp4_box(b"idat", b"short"));
+
+        let data = mp4_box(b"meta", &meta);
+
+        assert!(read_exif(&write_fixture("bad_idat.heic", &data)).is_err());
+    }
+}
@@ -0,0 +1,215 @@
+use std::io::{Read, Seek};
+
+use anyhow::{anyhow, Error};
+
+/// A single box header, with the offset and length of the box's contents.
+pub(crate) struct BoxHeader {
+    pub box_type: [u8; 4],
+    pub content_offset: u64,
+    pub content_size: u64,
+}
+
+/// Read a box header from the current position of the reader, returning None at the end of the file.
+pub(crate) fn read_box_header<R: Read + Seek>(
+    reader: &mut R,
+    file_size: u64,
+) -> Result<Option<BoxHeader>, Error> {
+    let start = reader.stream_position()?;
+
+    if start + 8 > file_size {
+        return Ok(None);
+    }
+
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header)?;
+
+    let mut box_type = [0u8; 4];
+    box_type.copy_from_slice(&header[4..8]);
+
+    let (header_size, box_size) = match u32::from_be_bytes(header[0..4].try_into()?) {
+        // Box extends to the end of the file
+        0 => (8, file_size - start),
+        // 64 bit box size follows the type
+        1 => {
+            let mut large_size = [0u8; 8];
+            reader.read_exact(&mut large_size)?;
+            (16, u64::from_be_bytes(large_size))
+        }
+        size => (8, size as u64),
+    };
+
+    // Corrupt 64 bit sizes can be large enough to overflow
+    if box_size < header_size
+        || start
+            .checked_add(box_size)
+            .is_none_or(|end| end > file_size)
+    {
+        return Err(anyhow!("Invalid box size {} at offset {}", box_size, start));
+    }
+
+    Ok(Some(BoxHeader {
+        box_type,
+        content_offset: start + header_size,
+        content_size: box_size - header_size,
+    }))
+}
+
+/// Iterate over the boxes contained in an in-memory slice, yielding each box's type and contents.
+pub(crate) fn child_boxes(data: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    let mut offset = 0usize;
+
+    std::iter::from_fn(move || {
+        if offset + 8 > data.len() {
+            return None;
+        }
+
+        let size = u32::from_be_bytes(data[offset..offset + 4].try_into().ok()?) as usize;
+        let mut box_type = [0u8; 4];
+        box_type.copy_from_slice(&data[offset + 4..offset + 8]);
+
+        let (header_size, box_size) = match size {
+            0 => (8, data.len() - offset),
+            1 => {
+                let large_size =
+                    u64::from_be_bytes(data.get(offset + 8..offset + 16)?.try_into().ok()?);
+                (16, usize::try_from(large_size).ok()?)
+            }
+            size => (8, size),
+        };
+
+        // Corrupt 64 bit sizes can be large enough to overflow
+        let end = offset.checked_add(box_size)?;
+
+        if box_size < header_size || end > data.len() {
+            return None;
+        }
+
+        let contents = &data[offset + header_size..end];
+        offset = end;
+
+        Some((box_type, contents))
+    })
+}
+
+/// Find the contents of the first child box of the given type.
+pub(crate) fn find_child<'a>(data: &'a [u8], box_type: &[u8; 4]) -> Option<&'a [u8]> {
+    child_boxes(data)
+        .find(|(candidate, _)| candidate == box_type)
+        .map(|(_, contents)| contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::test_util::mp4_box;
+
+    #[test]
+    fn child_boxes_yields_each_box() {
+        let mut data = mp4_box(b"ftyp", b"isom");
+        data.extend(mp4_box(b"free", b""));
+
+        let boxes = child_boxes(&data).collect::<Vec<([u8; 4], &[u8])>>();
+
+        assert_eq!(boxes, vec![(*b"ftyp", &b"isom"[..]), (*b"free", &b""[..])]);
+        assert_eq!(find_child(&data, b"free"), Some(&b""[..]));
+        assert_eq!(find_child(&data, b"moov"), None);
+    }
+
+    #[test]
+    fn child_boxes_handles_size_zero_and_64_bit_sizes() {
+        let mut data = vec![0, 0, 0, 1];
+        data.extend_from_slice(b"wide");
+        data.extend_from_slice(&20u64.to_be_bytes());
+        data.extend_from_slice(b"abcd");
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        data.extend_from_slice(b"rest");
+        data.extend_from_slice(b"tail");
+
+        let boxes = child_boxes(&data).collect::<Vec<([u8; 4], &[u8])>>();
+
+        assert_eq!(
+            boxes,
+            vec![(*b"wide", &b"abcd"[..]), (*b"rest", &b"tail"[..])]
+        );
+    }
+
+    #[test]
+    fn child_boxes_stops_at_truncated_or_oversized_boxes() {
+        let mut data = mp4_box(b"good", b"1234");
+        data.extend_from_slice(&100u32.to_be_bytes());
+        data.extend_from_slice(b"long");
+        assert_eq!(child_boxes(&data).count(), 1);
+
+        // Smaller than its own header
+        let mut data = 4u32.to_be_bytes().to_vec();
+        data.extend_from_slice(b"tiny");
+        assert_eq!(child_boxes(&data).count(), 0);
+
+        // A 64 bit size that would overflow when added to the offset
+        let mut data = mp4_box(b"good", b"1234");
+        data.extend_from_slice(&1u32.to_be_bytes());
+        data.extend_from_slice(b"huge");
+        data.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert_eq!(child_boxes(&data).count(), 1);
+
+        // Too short to hold the 64 bit size
+        let mut data = 1u32.to_be_bytes().to_vec();
+        data.extend_from_slice(b"huge");
+        data.extend_from_slice(&[0, 0]);
+        assert_eq!(child_boxes(&data).count(), 0);
+    }
+
+    #[test]
+    fn read_box_header_reads_sizes() {
+        let mut data = mp4_box(b"ftyp", b"isom");
+        data.extend_from_slice(&1u32.to_be_bytes());
+        data.extend_from_slice(b"mdat");
+        data.extend_from_slice(&20u64.to_be_bytes());
+        data.extend_from_slice(b"data");
+        let file_size = data.len() as u64;
+        let mut reader = Cursor::new(data);
+
+        let header = read_box_header(&mut reader, file_size).unwrap().unwrap();
+        assert_eq!(&header.box_type, b"ftyp");
+        assert_eq!((header.content_offset, header.content_size), (8, 4));
+
+        reader.set_position(header.content_offset + header.content_size);
+        let header = read_box_header(&mut reader, file_size).unwrap().unwrap();
+        assert_eq!(&header.box_type, b"mdat");
+        assert_eq!((header.content_offset, header.content_size), (28, 4));
+
+        reader.set_position(header.content_offset + header.content_size);
+        assert!(read_box_header(&mut reader, file_size).unwrap().is_none());
+    }
+
+    #[test]
+    fn read_box_header_rejects_truncated_or_oversized_boxes() {
+        let mut data = 100u32.to_be_bytes().to_vec();
+        data.extend_from_slice(b"moov");
+        let file_size = data.len() as u64;
+        assert!(read_box_header(&mut Cursor::new(data), file_size).is_err());
+
+        let mut data = 4u32.to_be_bytes().to_vec();
+        data.extend_from_slice(b"tiny");
+        let file_size = data.len() as u64;
+        assert!(read_box_header(&mut Cursor::new(data), file_size).is_err());
+
+        let mut data = 1u32.to_be_bytes().to_vec();
+        data.extend_from_slice(b"huge");
+        data.extend_from_slice(&u64::MAX.to_be_bytes());
+        let file_size = data.len() as u64;
+        assert!(read_box_header(&mut Cursor::new(data), file_size).is_err());
+
+        // A 64 bit size that only overflows once the box's start is added
+        let mut data = mp4_box(b"free", b"");
+        data.extend_from_slice(&1u32.to_be_bytes());
+        data.extend_from_slice(b"huge");
+        data.extend_from_slice(&(u64::MAX - 4).to_be_bytes());
+        let file_size = data.len() as u64;
+        let mut reader = Cursor::new(data);
+        reader.set_position(8);
+        assert!(read_box_header(&mut reader, file_size).is_err());
+    }
+}
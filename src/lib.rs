@@ -1 +1,15 @@
 pub mod config;
+pub mod db;
+pub mod directory;
+pub mod filename;
+pub mod group;
+pub mod heif;
+mod isobmff;
+pub mod live_photo;
+pub mod motion_photo;
+pub mod quicktime;
+pub mod raw;
+pub mod template;
+#[cfg(test)]
+mod test_util;
+pub mod timestamp;
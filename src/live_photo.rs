@@ -0,0 +1,175 @@
+/// Apple MakerNotes start with this, followed by a version and a byte order mark.
+const APPLE_MAKER_NOTE_SIGNATURE: &[u8] = b"Apple iOS\0";
+
+/// Offset of the IFD within an Apple MakerNote, after the signature, version and byte order mark.
+const APPLE_MAKER_NOTE_IFD_OFFSET: usize = 14;
+
+/// MakerNote tag holding the identifier shared by the photo and video of a Live Photo, which exiftool calls
+/// `MediaGroupUUID`.
+const CONTENT_IDENTIFIER_TAG: u16 = 0x0011;
+
+/// EXIF type for ASCII strings.
+const ASCII_TYPE: u16 = 2;
+
+/// Read the Live Photo content identifier from the raw bytes of an Apple MakerNote. Offsets within the
+/// MakerNote are relative to its start.
+pub fn content_identifier_from_maker_note(maker_note: &[u8]) -> Option<String> {
+    if !maker_note.starts_with(APPLE_MAKER_NOTE_SIGNATURE) {
+        return None;
+    }
+
+    let big_endian = match maker_note.get(12..14)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+
+    let u16_at = |offset: usize| -> Option<u16> {
+        let bytes = maker_note.get(offset..offset + 2)?.try_into().ok()?;
+        Some(match big_endian {
+            true => u16::from_be_bytes(bytes),
+            false => u16::from_le_bytes(bytes),
+        })
+    };
+
+    let u32_at = |offset: usize| -> Option<u32> {
+        let bytes = maker_note.get(offset..offset + 4)?.try_into().ok()?;
+        Some(match big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        })
+    };
+
+    let entry_count = u16_at(APPLE_MAKER_NOTE_IFD_OFFSET)? as usize;
+
+    for index in 0..entry_count {
+        let entry = APPLE_MAKER_NOTE_IFD_OFFSET + 2 + index * 12;
+
+        if u16_at(entry)? != CONTENT_IDENTIFIER_TAG || u16_at(entry + 2)? != ASCII_TYPE {
+            continue;
+        }
+
+        let count = u32_at(entry + 4)? as usize;
+
+        // Values of up to four bytes are stored in the entry itself
+        let value = match count {
+            0..=4 => maker_note.get(entry + 8..entry + 8 + count)?,
+            _ => {
+                let offset = u32_at(entry + 8)? as usize;
+                maker_note.get(offset..offset + count)?
+            }
+        };
+
+        let identifier = String::from_utf8_lossy(value)
+            .trim_end_matches('\0')
+            .to_string();
+
+        return Some(identifier).filter(|identifier| !identifier.is_empty());
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build an Apple MakerNote with an IFD holding the given ASCII entries, with values longer than four bytes
+    /// stored after the IFD.
+    fn maker_note(big_endian: bool, entries: &[(u16, &str)]) -> Vec<u8> {
+        let u16_bytes = |value: u16| match big_endian {
+            true => value.to_be_bytes(),
+            false => value.to_le_bytes(),
+        };
+        let u32_bytes = |value: u32| match big_endian {
+            true => value.to_be_bytes(),
+            false => value.to_le_bytes(),
+        };
+
+        let mut data = APPLE_MAKER_NOTE_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0, 1]);
+        data.extend_from_slice(match big_endian {
+            true => b"MM",
+            false => b"II",
+        });
+        data.extend_from_slice(&u16_bytes(entries.len() as u16));
+
+        let mut values_offset = APPLE_MAKER_NOTE_IFD_OFFSET + 2 + entries.len() * 12 + 4;
+        let mut values = vec![];
+
+        for (tag, value) in entries {
+            let mut value = value.as_bytes().to_vec();
+            value.push(0);
+
+            data.extend_from_slice(&u16_bytes(*tag));
+            data.extend_from_slice(&u16_bytes(ASCII_TYPE));
+            data.extend_from_slice(&u32_bytes(value.len() as u32));
+
+            match value.len() {
+                0..=4 => {
+                    value.resize(4, 0);
+                    data.extend_from_slice(&value);
+                }
+                _ => {
+                    data.extend_from_slice(&u32_bytes(values_offset as u32));
+                    values_offset += value.len();
+                    values.extend_from_slice(&value);
+                }
+            }
+        }
+
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        data.extend_from_slice(&values);
+        data
+    }
+
+    #[test]
+    fn reads_content_identifier_in_either_byte_order() {
+        for big_endian in [true, false] {
+            let data = maker_note(
+                big_endian,
+                &[
+                    (0x0008, "other"),
+                    (CONTENT_IDENTIFIER_TAG, "AAAA-1111-BBBB"),
+                ],
+            );
+
+            assert_eq!(
+                content_identifier_from_maker_note(&data).as_deref(),
+                Some("AAAA-1111-BBBB")
+            );
+        }
+    }
+
+    #[test]
+    fn reads_short_identifiers_from_the_entry() {
+        let data = maker_note(true, &[(CONTENT_IDENTIFIER_TAG, "abc")]);
+
+        assert_eq!(
+            content_identifier_from_maker_note(&data).as_deref(),
+            Some("abc")
+        );
+    }
+
+    #[test]
+    fn ignores_other_maker_notes_and_missing_identifiers() {
+        let data = maker_note(true, &[(0x0008, "other")]);
+        assert_eq!(content_identifier_from_maker_note(&data), None);
+
+        let mut data = maker_note(true, &[(CONTENT_IDENTIFIER_TAG, "AAAA-1111-BBBB")]);
+        data[0..5].copy_from_slice(b"Nikon");
+        assert_eq!(content_identifier_from_maker_note(&data), None);
+
+        let data = maker_note(true, &[(CONTENT_IDENTIFIER_TAG, "")]);
+        assert_eq!(content_identifier_from_maker_note(&data), None);
+    }
+
+    #[test]
+    fn rejects_truncated_maker_notes() {
+        let data = maker_note(true, &[(CONTENT_IDENTIFIER_TAG, "AAAA-1111-BBBB")]);
+
+        for length in [0, 12, 15, 20, data.len() - 4] {
+            assert_eq!(content_identifier_from_maker_note(&data[..length]), None);
+        }
+    }
+}
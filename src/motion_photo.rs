@@ -0,0 +1,321 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{anyhow, Error};
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+
+/// Identifies an APP1 segment holding XMP rather than EXIF data.
+const XMP_SIGNATURE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+/// XMP properties which mark a JPEG as having a video appended, and their values once it's been stripped.
+const MOTION_PHOTO_FLAGS: [(&str, &str); 2] = [
+    ("GCamera:MotionPhoto=\"1\"", "GCamera:MotionPhoto=\"0\""),
+    ("GCamera:MicroVideo=\"1\"", "GCamera:MicroVideo=\"0\""),
+];
+
+/// What to do with the video appended to Google Motion Photos, such as `MVIMG_*.jpg` and `PXL_*.MP.jpg`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MotionPhotoMode {
+    /// Transfer the file as it is, with the video still inside
+    #[default]
+    Keep,
+    /// Also write the video out as a separate MP4 next to the still
+    Extract,
+    /// Write the video out as a separate MP4, and strip it from the still
+    Split,
+}
+
+/// Where the video is within a motion photo, and where its XMP packet is so the still can be updated if the
+/// video is stripped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EmbeddedVideo {
+    /// Offset of the video from the start of the file, which is also the length of the still
+    pub offset: u64,
+    pub length: u64,
+    xmp_offset: u64,
+    xmp_length: u64,
+}
+
+/// Find the XMP packet in a JPEG's APP1 segments, returning its text along with its offset and length.
+fn read_xmp<R: Read + Seek>(reader: &mut R) -> Result<Option<(String, u64, u64)>, Error> {
+    let mut marker = [0u8; 2];
+    reader.read_exact(&mut marker)?;
+
+    if marker != [0xFF, 0xD8] {
+        return Err(anyhow!("Not a JPEG file"));
+    }
+
+    loop {
+        reader.read_exact(&mut marker)?;
+
+        if marker[0] != 0xFF {
+            return Err(anyhow!("Invalid JPEG segment marker"));
+        }
+
+        // Metadata segments all come before the start of the image data
+        if marker[1] == 0xDA || marker[1] == 0xD9 {
+            return Ok(None);
+        }
+
+        let mut length = [0u8; 2];
+        reader.read_exact(&mut length)?;
+        let content_length = (u16::from_be_bytes(length) as u64).saturating_sub(2);
+        let content_offset = reader.stream_position()?;
+
+        if marker[1] == 0xE1 && content_length > XMP_SIGNATURE.len() as u64 {
+            let mut content = vec![0u8; content_length as usize];
+            reader.read_exact(&mut content)?;
+
+            if content.starts_with(XMP_SIGNATURE) {
+                let xmp_offset = content_offset + XMP_SIGNATURE.len() as u64;
+                let xmp_length = content_length - XMP_SIGNATURE.len() as u64;
+                let xmp = String::from_utf8_lossy(&content[XMP_SIGNATURE.len()..]).to_string();
+
+                return Ok(Some((xmp, xmp_offset, xmp_length)));
+            }
+
+            continue;
+        }
+
+        reader.seek(SeekFrom::Start(content_offset + content_length))?;
+    }
+}
+
+/// Work out how many bytes from the end of the file the video starts, from either the newer
+/// `Container:Directory`, where the video and anything after it are listed with their lengths, or the older
+/// `GCamera:MicroVideoOffset`.
+fn video_offset_from_end(xmp: &str) -> Option<u64> {
+    let item_regex = Regex::new(r"<Container:Item\b([^>]*)>").unwrap();
+    let attribute_regex = Regex::new(r#"Item:(\w+)\s*=\s*"([^"]*)""#).unwrap();
+
+    let items = item_regex
+        .captures_iter(xmp)
+        .map(|item| {
+            attribute_regex
+                .captures_iter(&item[1])
+                .map(|attribute| (attribute[1].to_string(), attribute[2].to_string()))
+                .collect::<Vec<(String, String)>>()
+        })
+        .collect::<Vec<Vec<(String, String)>>>();
+
+    let attribute = |item: &[(String, String)], name: &str| -> Option<String> {
+        item.iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.clone())
+    };
+
+    let video_index = items.iter().position(|item| {
+        attribute(item, "Semantic").as_deref() == Some("MotionPhoto")
+            || attribute(item, "Mime").is_some_and(|mime| mime.starts_with("video/"))
+    });
+
+    if let Some(video_index) = video_index {
+        return items[video_index..]
+            .iter()
+            .map(|item| attribute(item, "Length")?.parse::<u64>().ok())
+            .sum();
+    }
+
+    Regex::new(r#"GCamera:MicroVideoOffset(?:\s*=\s*"|>)(\d+)"#)
+        .unwrap()
+        .captures(xmp)?[1]
+        .parse()
+        .ok()
+}
+
+/// Find the video embedded in a Google Motion Photo, returning None for ordinary JPEGs. The video is only
+/// accepted if it starts where the metadata says it does, to avoid cutting up files with bad metadata.
+pub fn find_embedded_video(path: &Path) -> Result<Option<EmbeddedVideo>, Error> {
+    let input_file = File::open(path)?;
+    let file_size = input_file.metadata()?.len();
+    let mut reader = BufReader::new(input_file);
+
+    let (xmp, xmp_offset, xmp_length) = match read_xmp(&mut reader)? {
+        None => return Ok(None),
+        Some(xmp) => xmp,
+    };
+
+    let length = match video_offset_from_end(&xmp) {
+        Some(length) if length > 0 && length < file_size => length,
+        _ => return Ok(None),
+    };
+
+    let offset = file_size - length;
+
+    // MP4s start with an ftyp box
+    let mut header = [0u8; 8];
+    reader.seek(SeekFrom::Start(offset))?;
+
+    if reader.read_exact(&mut header).is_err() || &header[4..8] != b"ftyp" {
+        return Err(anyhow!(
+            "Motion photo metadata in {:?} doesn't point at a video",
+            path
+        ));
+    }
+
+    Ok(Some(EmbeddedVideo {
+        offset,
+        length,
+        xmp_offset,
+        xmp_length,
+    }))
+}
+
+/// Write the video embedded in a motion photo out to its own file.
+pub fn extract_video(
+    source: &Path,
+    embedded_video: &EmbeddedVideo,
+    destination: &Path,
+) -> Result<(), Error> {
+    let mut input_file = File::open(source)?;
+    input_file.seek(SeekFrom::Start(embedded_video.offset))?;
+
+    let mut output_file = File::create(destination)?;
+    let copied = std::io::copy(
+        &mut input_file.take(embedded_video.length),
+        &mut output_file,
+    )?;
+
+    if copied != embedded_video.length {
+        return Err(anyhow!("{:?} is shorter than expected", source));
+    }
+
+    Ok(())
+}
+
+/// Write a copy of a motion photo with the embedded video stripped off, clearing the XMP flags which say
+/// there's a video so that viewers treat it as an ordinary photo.
+pub fn write_still(
+    source: &Path,
+    embedded_video: &EmbeddedVideo,
+    destination: &Path,
+) -> Result<(), Error> {
+    let mut still = vec![0u8; embedded_video.offset as usize];
+    File::open(source)?.read_exact(&mut still)?;
+
+    let xmp_range = embedded_video.xmp_offset as usize
+        ..(embedded_video.xmp_offset + embedded_video.xmp_length) as usize;
+
+    if let Some(xmp) = still.get_mut(xmp_range) {
+        // The replacements are the same length, so nothing else in the file moves
+        for (flag, cleared_flag) in MOTION_PHOTO_FLAGS {
+            while let Some(position) = xmp
+                .windows(flag.len())
+                .position(|window| window == flag.as_bytes())
+            {
+                xmp[position..position + flag.len()].copy_from_slice(cleared_flag.as_bytes());
+            }
+        }
+    }
+
+    File::create(destination)?.write_all(&still)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ascii_tiff, jpeg, mp4_box, write_fixture};
+
+    /// Build an APP1 segment holding the given XMP.
+    fn xmp_segment(xmp: &str) -> Vec<u8> {
+        let mut content = XMP_SIGNATURE.to_vec();
+        content.extend_from_slice(xmp.as_bytes());
+
+        let mut segment = vec![0xFF, 0xE1];
+        segment.extend_from_slice(&((content.len() + 2) as u16).to_be_bytes());
+        segment.extend_from_slice(&content);
+        segment
+    }
+
+    fn container_directory(video_length: u64) -> String {
+        format!(
+            r#"<x:xmpmeta GCamera:MotionPhoto="1" GCamera:MicroVideoOffset="999">
+                <Container:Directory><rdf:Seq>
+                <rdf:li><Container:Item Item:Mime="image/jpeg" Item:Semantic="Primary" Item:Length="0"/></rdf:li>
+                <rdf:li><Container:Item Item:Mime="video/mp4" Item:Semantic="MotionPhoto" Item:Length="{}"/></rdf:li>
+                </rdf:Seq></Container:Directory></x:xmpmeta>"#,
+            video_length
+        )
+    }
+
+    #[test]
+    fn prefers_container_directory_over_micro_video_offset() {
+        assert_eq!(
+            video_offset_from_end(&container_directory(1000)),
+            Some(1000)
+        );
+    }
+
+    #[test]
+    fn includes_items_after_the_video() {
+        let xmp = r#"<Container:Item Item:Semantic="Primary" Item:Mime="image/jpeg"/>
+            <Container:Item Item:Mime="video/mp4" Item:Length="1000"/>
+            <Container:Item Item:Mime="image/jpeg" Item:Semantic="GainMap" Item:Length="50"/>"#;
+
+        assert_eq!(video_offset_from_end(xmp), Some(1050));
+    }
+
+    #[test]
+    fn falls_back_to_micro_video_offset() {
+        assert_eq!(
+            video_offset_from_end(r#"<rdf:Description GCamera:MicroVideoOffset="2000"/>"#),
+            Some(2000)
+        );
+        assert_eq!(
+            video_offset_from_end("<GCamera:MicroVideoOffset>3000</GCamera:MicroVideoOffset>"),
+            Some(3000)
+        );
+        assert_eq!(video_offset_from_end("<x:xmpmeta/>"), None);
+    }
+
+    #[test]
+    fn finds_extracts_and_strips_embedded_video() {
+        let video = mp4_box(b"ftyp", b"mp42isom");
+        let mut data = jpeg(
+            &ascii_tiff(&[(0x010f, "Google")]),
+            &xmp_segment(&container_directory(video.len() as u64)),
+        );
+        let still_length = data.len() as u64;
+        data.extend_from_slice(&video);
+
+        let source = write_fixture("PXL_motion.MP.jpg", &data);
+        let embedded_video = find_embedded_video(&source).unwrap().unwrap();
+
+        assert_eq!(embedded_video.offset, still_length);
+        assert_eq!(embedded_video.length, video.len() as u64);
+
+        let video_path = write_fixture("PXL_motion.mp4", b"");
+        extract_video(&source, &embedded_video, &video_path).unwrap();
+        assert_eq!(std::fs::read(&video_path).unwrap(), video);
+
+        let still_path = write_fixture("PXL_motion.jpg", b"");
+        write_still(&source, &embedded_video, &still_path).unwrap();
+        let still = std::fs::read(&still_path).unwrap();
+        let still_xmp = String::from_utf8_lossy(&still);
+
+        assert_eq!(still.len() as u64, still_length);
+        assert!(still_xmp.contains("GCamera:MotionPhoto=\"0\""));
+    }
+
+    #[test]
+    fn ignores_photos_without_video_and_rejects_bad_offsets() {
+        let plain = jpeg(&ascii_tiff(&[(0x010f, "Google")]), &[]);
+        assert_eq!(
+            find_embedded_video(&write_fixture("plain.jpg", &plain)).unwrap(),
+            None
+        );
+
+        let mut data = jpeg(
+            &ascii_tiff(&[(0x010f, "Google")]),
+            &xmp_segment(&container_directory(12)),
+        );
+        data.extend_from_slice(b"not a video!");
+
+        assert!(find_embedded_video(&write_fixture("bad.jpg", &data)).is_err());
+    }
+}
@@ -0,0 +1,366 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime};
+
+use crate::isobmff::{child_boxes, find_child, read_box_header};
+use crate::timestamp::CaptureTime;
+
+/// Don't try to load absurdly large `moov` boxes into memory, as they're almost certainly corrupt.
+const MAX_MOOV_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Apple's metadata key for the local recording time, including the UTC offset.
+const APPLE_CREATION_DATE_KEY: &[u8] = b"com.apple.quicktime.creationdate";
+
+/// Apple's metadata key for the identifier shared by the photo and video of a Live Photo.
+const APPLE_CONTENT_IDENTIFIER_KEY: &[u8] = b"com.apple.quicktime.content.identifier";
+
+/// Convert a timestamp in seconds since the MP4 epoch of 1904-01-01 UTC into local time.
+fn mp4_time_to_local(seconds: u64) -> Option<CaptureTime> {
+    // Zero is written by plenty of devices that don't know the time, so treat it as missing
+    if seconds == 0 {
+        return None;
+    }
+
+    let epoch = NaiveDate::from_ymd_opt(1904, 1, 1)?.and_hms_opt(0, 0, 0)?;
+    let utc_time = epoch.checked_add_signed(Duration::seconds(i64::try_from(seconds).ok()?))?;
+
+    Some(CaptureTime::from_utc(utc_time))
+}
+
+/// Read the creation time from a `mvhd` box, which is stored as UTC.
+fn parse_mvhd(mvhd: &[u8]) -> Option<CaptureTime> {
+    let seconds = match mvhd.first()? {
+        0 => u32::from_be_bytes(mvhd.get(4..8)?.try_into().ok()?) as u64,
+        1 => u64::from_be_bytes(mvhd.get(4..12)?.try_into().ok()?),
+        _ => return None,
+    };
+
+    mp4_time_to_local(seconds)
+}
+
+/// Parse the free-text date formats found in `©day` and similar atoms. Where an offset is present the time is
+/// kept as the local time it was recorded in, matching how EXIF dates are treated; UTC times are converted to
+/// the local time zone.
+fn parse_date_string(value: &str) -> Option<CaptureTime> {
+    let value = value.trim().trim_end_matches('\0');
+
+    if let Some(utc_value) = value.strip_suffix('Z') {
+        for format in ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S"] {
+            if let Ok(utc_time) = NaiveDateTime::parse_from_str(utc_value, format) {
+                return Some(CaptureTime::from_utc(utc_time));
+            }
+        }
+    }
+
+    for format in ["%Y-%m-%dT%H:%M:%S%.f%z", "%Y-%m-%dT%H:%M:%S%z"] {
+        if let Ok(offset_time) = DateTime::parse_from_str(value, format) {
+            return Some(CaptureTime::with_offset(
+                offset_time.naive_local(),
+                *offset_time.offset(),
+            ));
+        }
+    }
+
+    for format in [
+        "%Y-%m-%dT%H:%M:%S%.f",
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%d %H:%M:%S",
+    ] {
+        if let Ok(naive_time) = NaiveDateTime::parse_from_str(value, format) {
+            return Some(CaptureTime::naive(naive_time));
+        }
+    }
+
+    None
+}
+
+/// Read the text from an iTunes-style `data` box inside a metadata item.
+fn parse_data_text(item: &[u8]) -> Option<String> {
+    let data = find_child(item, b"data")?;
+
+    // Skip the type indicator and locale
+    Some(String::from_utf8_lossy(data.get(8..)?).to_string())
+}
+
+/// Read a date from an iTunes-style `data` box inside a metadata item.
+fn parse_data_box(item: &[u8]) -> Option<CaptureTime> {
+    parse_date_string(&parse_data_text(item)?)
+}
+
+/// Get the children of a `meta` box. ISO `meta` boxes are full boxes with a version and flags before their
+/// children, whereas QuickTime ones go straight into the children, which will start with a `hdlr` box.
+fn meta_children(meta: &[u8]) -> Option<&[u8]> {
+    match meta.get(4..8) {
+        Some(b"hdlr") => Some(meta),
+        _ => meta.get(4..),
+    }
+}
+
+/// Find the `ilst` item for one of Apple's named metadata keys, which are listed in a `keys` table and
+/// referred to from `ilst` by their index.
+fn find_keyed_item<'a>(children: &'a [u8], key: &[u8]) -> Option<&'a [u8]> {
+    let ilst = find_child(children, b"ilst")?;
+    let keys = find_child(children, b"keys")?;
+
+    let entry_count = u32::from_be_bytes(keys.get(4..8)?.try_into().ok()?);
+    let mut offset = 8usize;
+
+    for index in 1..=entry_count {
+        let size = u32::from_be_bytes(keys.get(offset..offset + 4)?.try_into().ok()?) as usize;
+        let name = keys.get(offset + 8..offset + size)?;
+
+        if name == key {
+            return child_boxes(ilst)
+                .find(|(item_type, _)| *item_type == index.to_be_bytes())
+                .map(|(_, item)| item);
+        }
+
+        offset += size.max(8);
+    }
+
+    None
+}
+
+/// Look through a `meta` box for a creation date, either via Apple's `keys` table or an iTunes `©day` item.
+fn parse_meta(meta: &[u8]) -> Option<CaptureTime> {
+    let children = meta_children(meta)?;
+
+    if let Some(date) = find_keyed_item(children, APPLE_CREATION_DATE_KEY).and_then(parse_data_box)
+    {
+        return Some(date);
+    }
+
+    parse_data_box(find_child(find_child(children, b"ilst")?, b"\xa9day")?)
+}
+
+/// Read a QuickTime-style `©day` atom directly inside `udta`, which holds a length, language code and text.
+fn parse_udta(udta: &[u8]) -> Option<CaptureTime> {
+    if let Some(meta) = find_child(udta, b"meta") {
+        if let Some(date) = parse_meta(meta) {
+            return Some(date);
+        }
+    }
+
+    let day = find_child(udta, b"\xa9day")?;
+    let length = u16::from_be_bytes(day.get(0..2)?.try_into().ok()?) as usize;
+
+    parse_date_string(&String::from_utf8_lossy(day.get(4..4 + length)?))
+}
+
+/// Read the whole `moov` box of an MP4 or QuickTime file, which holds all of its metadata.
+fn read_moov(path: &Path) -> Result<Vec<u8>, Error> {
+    let input_file = File::open(path)?;
+    let file_size = input_file.metadata()?.len();
+    let mut reader = BufReader::new(input_file);
+
+    while let Some(header) = read_box_header(&mut reader, file_size)? {
+        if &header.box_type != b"moov" {
+            reader.seek(SeekFrom::Start(header.content_offset + header.content_size))?;
+            continue;
+        }
+
+        if header.content_size > MAX_MOOV_SIZE {
+            return Err(anyhow!("moov box too large in {:?}", path));
+        }
+
+        let mut moov = vec![0u8; header.content_size as usize];
+        reader.read_exact(&mut moov)?;
+
+        return Ok(moov);
+    }
+
+    Err(anyhow!("No moov box found in {:?}", path))
+}
+
+/// Extract, where possible, the recording time of an MP4 or QuickTime file. Explicit date metadata is
+/// preferred, as it carries the time zone the video was recorded in, with the `mvhd` creation time as a
+/// fallback.
+pub fn extract_creation_time(path: &Path) -> Result<CaptureTime, Error> {
+    let moov = read_moov(path)?;
+
+    let tagged_date = find_child(&moov, b"udta")
+        .and_then(parse_udta)
+        .or_else(|| find_child(&moov, b"meta").and_then(parse_meta));
+
+    tagged_date
+        .or_else(|| find_child(&moov, b"mvhd").and_then(parse_mvhd))
+        .ok_or_else(|| anyhow!("No creation time available for {:?}", path))
+}
+
+/// Extract the identifier Apple writes to both halves of a Live Photo, if this is the video from one.
+pub fn extract_content_identifier(path: &Path) -> Result<Option<String>, Error> {
+    let moov = read_moov(path)?;
+
+    Ok(find_child(&moov, b"meta")
+        .and_then(meta_children)
+        .and_then(|children| find_keyed_item(children, APPLE_CONTENT_IDENTIFIER_KEY))
+        .and_then(parse_data_text)
+        .map(|identifier| identifier.trim_end_matches('\0').to_string())
+        .filter(|identifier| !identifier.is_empty()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{mp4_box, write_fixture};
+
+    /// Build an iTunes-style metadata item holding a text `data` box.
+    fn text_item(item_type: &[u8; 4], text: &str) -> Vec<u8> {
+        let mut data = vec![0, 0, 0, 1, 0, 0, 0, 0];
+        data.extend_from_slice(text.as_bytes());
+        mp4_box(item_type, &mp4_box(b"data", &data))
+    }
+
+    /// Build a QuickTime `meta` box, which has no version and flags, listing the given keys and their values.
+    fn keyed_meta(items: &[(&[u8], &str)]) -> Vec<u8> {
+        let mut keys = vec![0, 0, 0, 0];
+        keys.extend_from_slice(&(items.len() as u32).to_be_bytes());
+        let mut ilst = vec![];
+
+        for (index, (key, value)) in items.iter().enumerate() {
+            keys.extend(mp4_box(b"mdta", key));
+            ilst.extend(text_item(&(index as u32 + 1).to_be_bytes(), value));
+        }
+
+        let mut meta = mp4_box(b"hdlr", &[0u8; 24]);
+        meta.extend(mp4_box(b"keys", &keys));
+        meta.extend(mp4_box(b"ilst", &ilst));
+        mp4_box(b"meta", &meta)
+    }
+
+    fn movie(moov: &[u8]) -> Vec<u8> {
+        let mut data = mp4_box(b"ftyp", b"qt  ");
+        data.extend(mp4_box(b"moov", moov));
+        data.extend(mp4_box(b"mdat", b""));
+        data
+    }
+
+    #[test]
+    fn finds_keyed_items() {
+        let meta = keyed_meta(&[
+            (b"com.apple.quicktime.make", "Apple"),
+            (APPLE_CONTENT_IDENTIFIER_KEY, "AAAA-1111"),
+        ]);
+        let children = meta_children(&meta[8..]).unwrap();
+
+        assert_eq!(
+            find_keyed_item(children, APPLE_CONTENT_IDENTIFIER_KEY)
+                .and_then(parse_data_text)
+                .as_deref(),
+            Some("AAAA-1111")
+        );
+        assert!(find_keyed_item(children, APPLE_CREATION_DATE_KEY).is_none());
+    }
+
+    #[test]
+    fn handles_iso_and_quicktime_meta_boxes() {
+        let quicktime_meta = mp4_box(b"hdlr", &[0u8; 24]);
+        assert_eq!(meta_children(&quicktime_meta), Some(&quicktime_meta[..]));
+
+        let mut iso_meta = vec![0, 0, 0, 0];
+        iso_meta.extend_from_slice(&quicktime_meta);
+        assert_eq!(meta_children(&iso_meta), Some(&quicktime_meta[..]));
+    }
+
+    #[test]
+    fn parses_date_strings() {
+        assert_eq!(
+            parse_date_string("2023-05-05T10:10:10+0200")
+                .unwrap()
+                .to_string(),
+            "2023-05-05T10:10:10+02:00"
+        );
+        assert_eq!(
+            parse_date_string("2023-05-05 10:10:10")
+                .unwrap()
+                .to_string(),
+            "2023-05-05T10:10:10"
+        );
+        assert_eq!(
+            parse_date_string("2023-05-05T08:10:10Z"),
+            Some(CaptureTime::from_utc(
+                NaiveDate::from_ymd_opt(2023, 5, 5)
+                    .unwrap()
+                    .and_hms_opt(8, 10, 10)
+                    .unwrap()
+            ))
+        );
+        assert_eq!(parse_date_string("yesterday"), None);
+    }
+
+    #[test]
+    fn parses_mvhd_creation_time() {
+        // 2000-01-01T00:00:00Z is 3029529600 seconds after the MP4 epoch
+        let mut mvhd = vec![0, 0, 0, 0];
+        mvhd.extend_from_slice(&3_029_529_600u32.to_be_bytes());
+
+        let expected = CaptureTime::from_utc(
+            NaiveDate::from_ymd_opt(2000, 1, 1)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
+        );
+
+        assert_eq!(parse_mvhd(&mvhd), Some(expected));
+
+        let mut mvhd = vec![1, 0, 0, 0];
+        mvhd.extend_from_slice(&3_029_529_600u64.to_be_bytes());
+        assert_eq!(parse_mvhd(&mvhd), Some(expected));
+
+        assert_eq!(parse_mvhd(&[0, 0, 0, 0, 0, 0, 0, 0]), None);
+        assert_eq!(parse_mvhd(&[0, 0, 0, 0, 1]), None);
+    }
+
+    #[test]
+    fn prefers_apple_creation_date_over_mvhd() {
+        let mut mvhd = vec![0, 0, 0, 0];
+        mvhd.extend_from_slice(&3_029_529_600u32.to_be_bytes());
+
+        let mut moov = mp4_box(b"mvhd", &mvhd);
+        moov.extend(keyed_meta(&[(
+            APPLE_CREATION_DATE_KEY,
+            "2023-05-05T10:10:10+0200",
+        )]));
+
+        let path = write_fixture("creation_date.mov", &movie(&moov));
+
+        assert_eq!(
+            extract_creation_time(&path).unwrap().to_string(),
+            "2023-05-05T10:10:10+02:00"
+        );
+    }
+
+    #[test]
+    fn reads_udta_day_atom() {
+        let mut day = 19u16.to_be_bytes().to_vec();
+        day.extend_from_slice(&[0x15, 0xc7]);
+        day.extend_from_slice(b"2019-09-09 09:09:09");
+
+        let moov = mp4_box(b"udta", &mp4_box(b"\xa9day", &day));
+        let path = write_fixture("udta.mp4", &movie(&moov));
+
+        assert_eq!(
+            extract_creation_time(&path).unwrap().to_string(),
+            "2019-09-09T09:09:09"
+        );
+    }
+
+    #[test]
+    fn extracts_content_identifier() {
+        let moov = keyed_meta(&[(APPLE_CONTENT_IDENTIFIER_KEY, "AAAA-1111\0")]);
+        let path = write_fixture("live.mov", &movie(&moov));
+        assert_eq!(
+            extract_content_identifier(&path).unwrap().as_deref(),
+            Some("AAAA-1111")
+        );
+
+        let path = write_fixture("plain.mov", &movie(&mp4_box(b"free", b"")));
+        assert_eq!(extract_content_identifier(&path).unwrap(), None);
+
+        let path = write_fixture("no_moov.mov", &mp4_box(b"ftyp", b"qt  "));
+        assert!(extract_content_identifier(&path).is_err());
+    }
+}
@@ -0,0 +1,412 @@
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::{anyhow, Error};
+use exif::{Exif, Reader};
+
+use crate::isobmff::{child_boxes, find_child};
+
+/// UUID of the box holding Canon's CR3 metadata, which contains the `CMT*` TIFF blocks.
+const CANON_METADATA_UUID: [u8; 16] = [
+    0x85, 0xc0, 0xb6, 0x87, 0x82, 0x0f, 0x11, 0xe0, 0x81, 0x11, 0xf4, 0xce, 0x46, 0x2b, 0x6a, 0x48,
+];
+
+/// Tags whose values are offsets to other IFDs, and so need adjusting if their IFD is moved.
+const POINTER_TAGS: [u16; 3] = [0x8769, 0x8825, 0xa005];
+
+const EXIF_IFD_POINTER: u16 = 0x8769;
+const GPS_IFD_POINTER: u16 = 0x8825;
+
+/// How much of a RAW file is read at first when looking for its metadata, which every supported format keeps
+/// near the start. Should that not be enough, as some values may be stored further in, more is read each time
+/// until the whole file has been tried.
+const INITIAL_READ_LENGTH: u64 = 256 * 1024;
+
+/// The containers RAW files come in, as far as finding their metadata goes.
+enum RawContainer {
+    /// A TIFF file, along with the standard magic number to replace a vendor-specific one with, if it has one
+    Tiff(Option<[u8; 2]>),
+    /// Fujifilm RAF, which holds a JPEG preview with full EXIF at an offset given in the header
+    Raf,
+    /// Canon CR3, an ISO BMFF file with its metadata split across several TIFF blocks
+    Cr3,
+}
+
+fn identify_container(header: &[u8]) -> Option<RawContainer> {
+    match header.get(0..4) {
+        // Standard TIFF, as used by DNG, CR2, NEF, ARW and friends
+        Some(b"II*\0") | Some(b"MM\0*") => Some(RawContainer::Tiff(None)),
+        // Panasonic RW2 and Olympus ORF are TIFF with a vendor-specific magic number in place of 42
+        Some(b"IIU\0") | Some(b"IIRO") | Some(b"IIRS") => {
+            Some(RawContainer::Tiff(Some([0x2a, 0x00])))
+        }
+        Some(b"MMOR") => Some(RawContainer::Tiff(Some([0x00, 0x2a]))),
+        Some(b"FUJI") if header.starts_with(b"FUJIFILMCCD-RAW") => Some(RawContainer::Raf),
+        _ if header.get(4..12) == Some(b"ftypcrx ") => Some(RawContainer::Cr3),
+        _ => None,
+    }
+}
+
+/// Read the EXIF data from a RAW file. Most RAW formats are TIFF files under the hood, but several vendors
+/// change the TIFF magic number or wrap things up in their own container, so normalise those into plain
+/// TIFF data first. Only as much of the file as is needed is read, as RAWs are large and the metadata is
+/// usually a tiny part at the start.
+pub fn read_exif(path: &Path) -> Result<Exif, Error> {
+    let mut file = File::open(path)?;
+    let file_length = file.metadata()?.len();
+    let mut data = vec![];
+    let mut read_length = INITIAL_READ_LENGTH;
+
+    loop {
+        (&mut file)
+            .take(read_length - data.len() as u64)
+            .read_to_end(&mut data)?;
+
+        let container = identify_container(&data)
+            .ok_or_else(|| anyhow!("Unrecognised RAW format for {:?}", path))?;
+
+        let result = match container {
+            RawContainer::Tiff(magic) => {
+                let mut tiff = data.clone();
+
+                if let Some(magic) = magic {
+                    tiff[2..4].copy_from_slice(&magic);
+                }
+
+                Reader::new().read_raw(tiff).map_err(Error::from)
+            }
+            RawContainer::Raf => return read_raf_exif(&mut file, &data, path),
+            RawContainer::Cr3 => read_cr3_tiff(&data)
+                .ok_or_else(|| anyhow!("Unable to find CR3 metadata in {:?}", path))
+                .and_then(|tiff| Ok(Reader::new().read_raw(tiff)?)),
+        };
+
+        match result {
+            // The metadata may run on past what's been read so far, so try again with more of the file
+            Err(_) if (data.len() as u64) < file_length => read_length *= 8,
+            result => return result,
+        }
+    }
+}
+
+/// Read the EXIF data from the JPEG preview in a RAF file, reading just the preview rather than the whole file.
+fn read_raf_exif(file: &mut File, header: &[u8], path: &Path) -> Result<Exif, Error> {
+    let jpeg_offset = read_u32(header, 84, false)? as u64;
+    let jpeg_length = read_u32(header, 88, false)? as u64;
+
+    let mut jpeg = vec![];
+    file.seek(SeekFrom::Start(jpeg_offset))?;
+    file.take(jpeg_length).read_to_end(&mut jpeg)?;
+
+    if (jpeg.len() as u64) < jpeg_length {
+        return Err(anyhow!("Invalid RAF preview location in {:?}", path));
+    }
+
+    Ok(Reader::new().read_from_container(&mut Cursor::new(jpeg))?)
+}
+
+fn read_u16(data: &[u8], offset: usize, little_endian: bool) -> Result<u16, Error> {
+    let bytes: [u8; 2] = data
+        .get(offset..offset + 2)
+        .ok_or_else(|| anyhow!("Truncated TIFF data"))?
+        .try_into()?;
+
+    Ok(match little_endian {
+        true => u16::from_le_bytes(bytes),
+        false => u16::from_be_bytes(bytes),
+    })
+}
+
+fn read_u32(data: &[u8], offset: usize, little_endian: bool) -> Result<u32, Error> {
+    let bytes: [u8; 4] = data
+        .get(offset..offset + 4)
+        .ok_or_else(|| anyhow!("Truncated TIFF data"))?
+        .try_into()?;
+
+    Ok(match little_endian {
+        true => u32::from_le_bytes(bytes),
+        false => u32::from_be_bytes(bytes),
+    })
+}
+
+fn write_u16(data: &mut Vec<u8>, value: u16, little_endian: bool) {
+    match little_endian {
+        true => data.extend_from_slice(&value.to_le_bytes()),
+        false => data.extend_from_slice(&value.to_be_bytes()),
+    }
+}
+
+fn write_u32(data: &mut Vec<u8>, value: u32, little_endian: bool) {
+    match little_endian {
+        true => data.extend_from_slice(&value.to_le_bytes()),
+        false => data.extend_from_slice(&value.to_be_bytes()),
+    }
+}
+
+/// The size in bytes of a single value of each TIFF field type.
+fn tiff_type_size(field_type: u16) -> usize {
+    match field_type {
+        3 | 8 => 2,
+        4 | 9 | 11 => 4,
+        5 | 10 | 12 => 8,
+        _ => 1,
+    }
+}
+
+/// A TIFF block from a CR3 file, appended to a combined buffer at the given offset.
+struct TiffBlock {
+    offset: usize,
+    ifd_offset: usize,
+}
+
+/// Copy the IFD entries of a TIFF block that has been appended to a larger buffer, adjusting any offsets so
+/// they remain valid relative to the start of that buffer.
+fn rebased_ifd_entries(
+    combined: &[u8],
+    block: &TiffBlock,
+    little_endian: bool,
+) -> Result<Vec<(u16, u16, u32, u32)>, Error> {
+    let ifd_start = block.offset + block.ifd_offset;
+    let entry_count = read_u16(combined, ifd_start, little_endian)? as usize;
+    let mut entries = vec![];
+
+    for index in 0..entry_count {
+        let entry_start = ifd_start + 2 + index * 12;
+        let tag = read_u16(combined, entry_start, little_endian)?;
+        let field_type = read_u16(combined, entry_start + 2, little_endian)?;
+        let count = read_u32(combined, entry_start + 4, little_endian)?;
+        let mut value = read_u32(combined, entry_start + 8, little_endian)?;
+
+        if tiff_type_size(field_type).saturating_mul(count as usize) > 4
+            || POINTER_TAGS.contains(&tag)
+        {
+            value = u32::try_from(block.offset)
+                .ok()
+                .and_then(|offset| value.checked_add(offset))
+                .ok_or_else(|| anyhow!("IFD offset out of range in CR3 metadata"))?;
+        }
+
+        entries.push((tag, field_type, count, value));
+    }
+
+    Ok(entries)
+}
+
+/// Write a new IFD to the end of a buffer, returning its offset.
+fn append_ifd(
+    combined: &mut Vec<u8>,
+    entries: &mut [(u16, u16, u32, u32)],
+    little_endian: bool,
+) -> usize {
+    if !combined.len().is_multiple_of(2) {
+        combined.push(0);
+    }
+
+    let ifd_offset = combined.len();
+
+    entries.sort_by_key(|entry| entry.0);
+
+    write_u16(combined, entries.len() as u16, little_endian);
+
+    for (tag, field_type, count, value) in entries.iter() {
+        write_u16(combined, *tag, little_endian);
+        write_u16(combined, *field_type, little_endian);
+        write_u32(combined, *count, little_endian);
+        write_u32(combined, *value, little_endian);
+    }
+
+    write_u32(combined, 0, little_endian);
+
+    ifd_offset
+}
+
+/// CR3 files store their metadata as several separate TIFF blocks: `CMT1` holds IFD0, `CMT2` the EXIF IFD
+/// and `CMT4` the GPS IFD. Stitch these together into a single TIFF, with IFD0 pointing at the others, so
+/// that the EXIF parser sees each tag in the right context.
+fn read_cr3_tiff(data: &[u8]) -> Option<Vec<u8>> {
+    let moov = find_child(data, b"moov")?;
+
+    // There may be several uuid boxes, so look for the one holding Canon's metadata
+    let canon_box = child_boxes(moov)
+        .find(|(box_type, contents)| {
+            box_type == b"uuid" && contents.get(0..16) == Some(&CANON_METADATA_UUID[..])
+        })
+        .map(|(_, contents)| &contents[16..]);
+
+    let canon_box = canon_box?;
+    let cmt1 = find_child(canon_box, b"CMT1")?;
+    let little_endian = cmt1.get(0..2)? == b"II";
+
+    let mut combined = cmt1.to_vec();
+    let mut blocks = vec![];
+
+    for block_type in [b"CMT1", b"CMT2", b"CMT4"] {
+        let block_data = match find_child(canon_box, block_type) {
+            None => {
+                blocks.push(None);
+                continue;
+            }
+            Some(block_data) => block_data,
+        };
+
+        // Every block is a standalone TIFF, but they all share the byte order of CMT1
+        if block_data.get(0..2)? != cmt1.get(0..2)? {
+            blocks.push(None);
+            continue;
+        }
+
+        let block_offset = match block_type {
+            b"CMT1" => 0,
+            _ => {
+                while !combined.len().is_multiple_of(4) {
+                    combined.push(0);
+                }
+                let block_offset = combined.len();
+                combined.extend_from_slice(block_data);
+                block_offset
+            }
+        };
+
+        blocks.push(Some(TiffBlock {
+            offset: block_offset,
+            ifd_offset: read_u32(block_data, 4, little_endian).ok()? as usize,
+        }));
+    }
+
+    let mut ifd0_entries = match &blocks[0] {
+        Some(block) => rebased_ifd_entries(&combined, block, little_endian).ok()?,
+        None => return None,
+    };
+
+    // Replace any existing pointers with ones to the relocated EXIF and GPS IFDs
+    for (pointer_tag, block) in [
+        (EXIF_IFD_POINTER, &blocks[1]),
+        (GPS_IFD_POINTER, &blocks[2]),
+    ] {
+        ifd0_entries.retain(|entry| entry.0 != pointer_tag);
+
+        if let Some(block) = block {
+            let mut entries = rebased_ifd_entries(&combined, block, little_endian).ok()?;
+            let ifd_offset = append_ifd(&mut combined, &mut entries, little_endian);
+
+            ifd0_entries.push((pointer_tag, 4, 1, ifd_offset as u32));
+        }
+    }
+
+    let ifd0_offset = append_ifd(&mut combined, &mut ifd0_entries, little_endian) as u32;
+
+    let header_offset = match little_endian {
+        true => ifd0_offset.to_le_bytes(),
+        false => ifd0_offset.to_be_bytes(),
+    };
+    combined[4..8].copy_from_slice(&header_offset);
+
+    Some(combined)
+}
+
+#[cfg(test)]
+mod tests {
+    use exif::{In, Tag};
+
+    use super::*;
+    use crate::test_util::{ascii_tiff, jpeg, mp4_box, write_fixture};
+
+    fn field_value(exif_data: &Exif, tag: Tag) -> Option<String> {
+        exif_data
+            .get_field(tag, In::PRIMARY)
+            .map(|field| field.display_value().to_string())
+    }
+
+    #[test]
+    fn reads_vendor_tiff_magic() {
+        for (name, magic) in [("rw2.rw2", b"IIU\0"), ("orf.orf", b"IIRO")] {
+            let mut data = ascii_tiff(&[(0x010f, "Vendor")]);
+            data[0..4].copy_from_slice(magic);
+
+            let exif_data = read_exif(&write_fixture(name, &data)).unwrap();
+            assert_eq!(
+                field_value(&exif_data, Tag::Make).as_deref(),
+                Some("\"Vendor\"")
+            );
+        }
+    }
+
+    #[test]
+    fn reads_values_beyond_the_initial_read() {
+        let description = "x".repeat(INITIAL_READ_LENGTH as usize * 2);
+        let data = ascii_tiff(&[(0x010e, &description), (0x010f, "Vendor")]);
+
+        let exif_data = read_exif(&write_fixture("large.dng", &data)).unwrap();
+        assert_eq!(
+            field_value(&exif_data, Tag::Make).as_deref(),
+            Some("\"Vendor\"")
+        );
+    }
+
+    #[test]
+    fn rejects_unrecognised_formats() {
+        assert!(read_exif(&write_fixture("unknown.raw", b"not a raw file")).is_err());
+    }
+
+    #[test]
+    fn reads_raf_preview_at_header_offset() {
+        let preview = jpeg(&ascii_tiff(&[(0x010f, "FUJIFILM")]), &[]);
+
+        let mut data = b"FUJIFILMCCD-RAW 0201FF383501".to_vec();
+        data.resize(84, 0);
+        data.extend_from_slice(&100u32.to_be_bytes());
+        data.extend_from_slice(&(preview.len() as u32).to_be_bytes());
+        data.resize(100, 0);
+        data.extend_from_slice(&preview);
+
+        let exif_data = read_exif(&write_fixture("preview.raf", &data)).unwrap();
+        assert_eq!(
+            field_value(&exif_data, Tag::Make).as_deref(),
+            Some("\"FUJIFILM\"")
+        );
+
+        // A preview running past the end of the file
+        data[88..92].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(read_exif(&write_fixture("truncated.raf", &data)).is_err());
+    }
+
+    #[test]
+    fn stitches_cr3_metadata_blocks() {
+        let mut canon_box = CANON_METADATA_UUID.to_vec();
+        canon_box.extend(mp4_box(b"CMT1", &ascii_tiff(&[(0x010f, "Canon")])));
+        canon_box.extend(mp4_box(
+            b"CMT2",
+            &ascii_tiff(&[(0x9003, "2022:03:04 05:06:07"), (0x9010, "+01:00")]),
+        ));
+
+        let mut moov = mp4_box(b"uuid", &[0u8; 20]);
+        moov.extend(mp4_box(b"uuid", &canon_box));
+
+        let mut data = mp4_box(b"ftyp", b"crx \0\0\0\x01");
+        data.extend(mp4_box(b"moov", &moov));
+
+        let exif_data = read_exif(&write_fixture("stitched.cr3", &data)).unwrap();
+
+        assert_eq!(
+            field_value(&exif_data, Tag::Make).as_deref(),
+            Some("\"Canon\"")
+        );
+        assert_eq!(
+            field_value(&exif_data, Tag::DateTimeOriginal).as_deref(),
+            Some("2022-03-04 05:06:07")
+        );
+        assert_eq!(
+            field_value(&exif_data, Tag::OffsetTime).as_deref(),
+            Some("\"+01:00\"")
+        );
+    }
+
+    #[test]
+    fn rejects_cr3_without_metadata() {
+        let mut data = mp4_box(b"ftyp", b"crx \0\0\0\x01");
+        data.extend(mp4_box(b"moov", &mp4_box(b"uuid", &[0u8; 20])));
+
+        assert!(read_exif(&write_fixture("empty.cr3", &data)).is_err());
+    }
+}
@@ -0,0 +1,686 @@
+use std::fmt;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use chrono::format::{Fixed, Item, StrftimeItems};
+use chrono::{FixedOffset, TimeZone};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::timestamp::CaptureTime;
+
+/// The template used when none is specified in the config, matching the historic naming scheme of
+/// `YYYYMMDD_HHMMSS[.counter][.mp].ext`.
+pub static DEFAULT_FILENAME_TEMPLATE: &str = "{date:%Y%m%d_%H%M%S}[.{counter}][.{mp}].{ext}";
+
+/// A single substitutable value within a template.
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    /// The resolved date, formatted with the given chrono format string
+    Date(String),
+    /// Collision counter, empty for the first attempt at a name
+    Counter,
+    /// The original file stem
+    Stem,
+    /// The camera make, from EXIF, if known
+    Make,
+    /// The camera model, from EXIF, if known
+    Model,
+    /// The original file extension
+    Extension,
+    /// `mp` for Google motion photos, otherwise empty
+    MotionPhoto,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Token(Token),
+    /// A group of segments that is only rendered if every token inside it has a non-empty value
+    Optional(Vec<Segment>),
+}
+
+/// All of the values that may be substituted into a filename template for a single file.
+pub struct TemplateValues<'a> {
+    pub date: &'a CaptureTime,
+    pub counter: u32,
+    pub stem: &'a str,
+    pub make: Option<&'a str>,
+    pub model: Option<&'a str>,
+    pub extension: &'a str,
+    pub motion_photo: bool,
+}
+
+/// A parsed template. Tokens are written as `{name}`, and any text wrapped in `[...]` is only included if all
+/// tokens inside it have a value, which allows separators to disappear along with an empty counter or
+/// marker. Supported tokens are:
+///
+/// - `{date:FORMAT}` - the resolved date, using a chrono format string
+/// - `{year}`, `{month}`, `{day}`, `{hour}`, `{minute}`, `{second}` - zero-padded shorthands for the above
+/// - `{counter}` - the collision counter, empty unless the name is already taken
+/// - `{stem}` - the original filename without extension
+/// - `{make}` / `{model}` - the camera make and model from EXIF
+/// - `{ext}` - the original extension
+/// - `{mp}` - `mp` for motion photos, empty otherwise
+#[derive(Clone, Debug, PartialEq)]
+struct Template {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    /// Parse a template, optionally allowing `/` to be used to separate directory levels.
+    fn parse(template: &str, allow_separators: bool) -> Result<Self, TemplateError> {
+        let mut segments: Vec<Segment> = vec![];
+        let mut optional: Option<Vec<Segment>> = None;
+        let mut literal = String::new();
+        let mut chars = template.chars();
+
+        // Pushes a segment to whichever list is currently being built, the optional group or the top level
+        fn push(
+            segments: &mut Vec<Segment>,
+            optional: &mut Option<Vec<Segment>>,
+            segment: Segment,
+        ) {
+            match optional {
+                Some(group) => group.push(segment),
+                None => segments.push(segment),
+            }
+        }
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' => {
+                    if !literal.is_empty() {
+                        push(
+                            &mut segments,
+                            &mut optional,
+                            Segment::Literal(literal.clone()),
+                        );
+                        literal.clear();
+                    }
+
+                    let mut token_text = String::new();
+                    let mut closed = false;
+
+                    for token_char in chars.by_ref() {
+                        if token_char == '}' {
+                            closed = true;
+                            break;
+                        }
+                        token_text.push(token_char);
+                    }
+
+                    if !closed {
+                        return Err(TemplateError(format!(
+                            "unterminated token '{{{}' in template '{}'",
+                            token_text, template
+                        )));
+                    }
+
+                    let token = Self::parse_token(&token_text, template, allow_separators)?;
+                    push(&mut segments, &mut optional, Segment::Token(token));
+                }
+                '[' => {
+                    if optional.is_some() {
+                        return Err(TemplateError(format!(
+                            "optional groups cannot be nested in template '{}'",
+                            template
+                        )));
+                    }
+
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(literal.clone()));
+                        literal.clear();
+                    }
+
+                    optional = Some(vec![]);
+                }
+                ']' => {
+                    let mut group = match optional.take() {
+                        None => {
+                            return Err(TemplateError(format!(
+                                "unmatched ']' in template '{}'",
+                                template
+                            )));
+                        }
+                        Some(group) => group,
+                    };
+
+                    if !literal.is_empty() {
+                        group.push(Segment::Literal(literal.clone()));
+                        literal.clear();
+                    }
+
+                    segments.push(Segment::Optional(group));
+                }
+                '}' => {
+                    return Err(TemplateError(format!(
+                        "unmatched '}}' in template '{}'",
+                        template
+                    )));
+                }
+                '/' if allow_separators => literal.push(c),
+                '/' | '\\' => {
+                    return Err(TemplateError(format!(
+                        "path separators are not allowed in template '{}'",
+                        template
+                    )));
+                }
+                _ => literal.push(c),
+            }
+        }
+
+        if optional.is_some() {
+            return Err(TemplateError(format!(
+                "unterminated '[' in template '{}'",
+                template
+            )));
+        }
+
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        Ok(Template {
+            source: template.to_string(),
+            segments,
+        })
+    }
+
+    fn parse_token(
+        token_text: &str,
+        template: &str,
+        allow_separators: bool,
+    ) -> Result<Token, TemplateError> {
+        let (name, argument) = match token_text.split_once(':') {
+            None => (token_text, None),
+            Some((name, argument)) => (name, Some(argument)),
+        };
+
+        let token = match (name, argument) {
+            ("date", Some(format)) => {
+                if format.is_empty()
+                    || StrftimeItems::new(format).any(|item| matches!(item, Item::Error))
+                {
+                    return Err(TemplateError(format!(
+                        "invalid date format '{}' in template '{}'",
+                        format, template
+                    )));
+                }
+
+                // Formats such as %D write separators of their own, so check what they actually produce
+                let sample = FixedOffset::east_opt(3600)
+                    .unwrap()
+                    .with_ymd_and_hms(2001, 2, 3, 4, 5, 6)
+                    .unwrap()
+                    .format(format)
+                    .to_string();
+
+                if sample.contains('\\') || (!allow_separators && sample.contains('/')) {
+                    return Err(TemplateError(format!(
+                        "date format '{}' produces path separators in template '{}'",
+                        format, template
+                    )));
+                }
+
+                Token::Date(format.to_string())
+            }
+            ("year", None) => Token::Date(String::from("%Y")),
+            ("month", None) => Token::Date(String::from("%m")),
+            ("day", None) => Token::Date(String::from("%d")),
+            ("hour", None) => Token::Date(String::from("%H")),
+            ("minute", None) => Token::Date(String::from("%M")),
+            ("second", None) => Token::Date(String::from("%S")),
+            ("counter", None) => Token::Counter,
+            ("stem", None) => Token::Stem,
+            ("make", None) => Token::Make,
+            ("model", None) => Token::Model,
+            ("ext", None) => Token::Extension,
+            ("mp", None) => Token::MotionPhoto,
+            _ => {
+                return Err(TemplateError(format!(
+                    "unknown token '{{{}}}' in template '{}'",
+                    token_text, template
+                )));
+            }
+        };
+
+        Ok(token)
+    }
+
+    fn contains_token(&self, token: &Token) -> bool {
+        self.segments.iter().any(|segment| match segment {
+            Segment::Token(candidate) => candidate == token,
+            Segment::Optional(group) => group
+                .iter()
+                .any(|inner| matches!(inner, Segment::Token(candidate) if candidate == token)),
+            Segment::Literal(_) => false,
+        })
+    }
+
+    fn uses_camera_details(&self) -> bool {
+        self.contains_token(&Token::Make) || self.contains_token(&Token::Model)
+    }
+
+    /// Whether a token only renders when other tokens have values, as it's in an optional group with them.
+    fn depends_on_other_tokens(&self, token: &Token) -> bool {
+        self.segments.iter().any(|segment| match segment {
+            Segment::Optional(group) => {
+                let tokens = group
+                    .iter()
+                    .filter_map(|inner| match inner {
+                        Segment::Token(candidate) => Some(candidate),
+                        _ => None,
+                    })
+                    .collect::<Vec<&Token>>();
+
+                tokens.contains(&token) && tokens.iter().any(|candidate| *candidate != token)
+            }
+            _ => false,
+        })
+    }
+
+    fn render_token(token: &Token, values: &TemplateValues) -> String {
+        match token {
+            Token::Date(format) => format_date(values.date, format),
+            Token::Counter => match values.counter {
+                0 => String::new(),
+                counter => counter.to_string(),
+            },
+            Token::Stem => values.stem.to_string(),
+            Token::Make => sanitise_component(values.make.unwrap_or_default()),
+            Token::Model => sanitise_component(values.model.unwrap_or_default()),
+            Token::Extension => values.extension.to_string(),
+            Token::MotionPhoto => match values.motion_photo {
+                true => String::from("mp"),
+                false => String::new(),
+            },
+        }
+    }
+
+    fn render(&self, values: &TemplateValues) -> String {
+        let mut output = String::new();
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(text) => output.push_str(text),
+                Segment::Token(token) => output.push_str(&Self::render_token(token, values)),
+                Segment::Optional(group) => {
+                    let mut group_output = String::new();
+                    let mut complete = true;
+
+                    for inner in group {
+                        match inner {
+                            Segment::Literal(text) => group_output.push_str(text),
+                            Segment::Token(token) => {
+                                let rendered = Self::render_token(token, values);
+
+                                if rendered.is_empty() {
+                                    complete = false;
+                                    break;
+                                }
+
+                                group_output.push_str(&rendered);
+                            }
+                            Segment::Optional(_) => unreachable!("optional groups cannot nest"),
+                        }
+                    }
+
+                    if complete {
+                        output.push_str(&group_output);
+                    }
+                }
+            }
+        }
+
+        output
+    }
+}
+
+/// A template for output filenames, which must contain `{counter}` and `{ext}` tokens, outside of any optional
+/// group with other tokens, and no path separators.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct FilenameTemplate(Template);
+
+impl FilenameTemplate {
+    pub fn parse(template: &str) -> Result<Self, TemplateError> {
+        let parsed = Template::parse(template, false)?;
+
+        // Without a counter, we'd have no way of picking a new name when two files resolve to the same one
+        if !parsed.contains_token(&Token::Counter) {
+            return Err(TemplateError(format!(
+                "template '{}' must contain a {{counter}} token to resolve name collisions",
+                template
+            )));
+        }
+
+        // The counter would never be rendered for files where the other tokens in its group are empty
+        if parsed.depends_on_other_tokens(&Token::Counter) {
+            return Err(TemplateError(format!(
+                "{{counter}} must not share an optional group with other tokens in template '{}'",
+                template
+            )));
+        }
+
+        // Files in a group share a name apart from the extension, so without it they'd collide with each other
+        if !parsed.contains_token(&Token::Extension)
+            || parsed.depends_on_other_tokens(&Token::Extension)
+        {
+            return Err(TemplateError(format!(
+                "template '{}' must contain an {{ext}} token, outside of any optional group with other tokens",
+                template
+            )));
+        }
+
+        Ok(FilenameTemplate(parsed))
+    }
+
+    /// Whether rendering this template needs the camera make or model, which requires an extra EXIF read.
+    pub fn uses_camera_details(&self) -> bool {
+        self.0.uses_camera_details()
+    }
+
+    /// Produce a filename from this template for the given values.
+    pub fn render(&self, values: &TemplateValues) -> String {
+        self.0.render(values)
+    }
+}
+
+impl Default for FilenameTemplate {
+    fn default() -> Self {
+        FilenameTemplate::parse(DEFAULT_FILENAME_TEMPLATE).unwrap()
+    }
+}
+
+impl TryFrom<String> for FilenameTemplate {
+    type Error = TemplateError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        FilenameTemplate::parse(&value)
+    }
+}
+
+impl From<FilenameTemplate> for String {
+    fn from(value: FilenameTemplate) -> Self {
+        value.0.source
+    }
+}
+
+/// A template for the directory hierarchy files are placed in, relative to the output roots. Levels are
+/// separated with `/`, and an empty template places everything directly in the output root.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct DirectoryTemplate(Template);
+
+impl DirectoryTemplate {
+    pub fn parse(template: &str) -> Result<Self, TemplateError> {
+        let parsed = Template::parse(template, true)?;
+
+        if parsed.contains_token(&Token::Counter) {
+            return Err(TemplateError(format!(
+                "{{counter}} cannot be used in directory template '{}'",
+                template
+            )));
+        }
+
+        if template.starts_with('/') || template.split('/').any(|level| level == "..") {
+            return Err(TemplateError(format!(
+                "directory template '{}' must stay within the output directory",
+                template
+            )));
+        }
+
+        Ok(DirectoryTemplate(parsed))
+    }
+
+    /// Whether rendering this template needs the camera make or model, which requires an extra EXIF read.
+    pub fn uses_camera_details(&self) -> bool {
+        self.0.uses_camera_details()
+    }
+
+    /// Produce a relative directory path from this template for the given values. Any levels that render
+    /// as empty are skipped.
+    pub fn render(&self, values: &TemplateValues) -> PathBuf {
+        self.0
+            .render(values)
+            .split('/')
+            .filter(|level| !level.is_empty() && *level != "." && *level != "..")
+            .collect()
+    }
+}
+
+impl Default for DirectoryTemplate {
+    fn default() -> Self {
+        DirectoryTemplate::parse("").unwrap()
+    }
+}
+
+impl TryFrom<String> for DirectoryTemplate {
+    type Error = TemplateError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        DirectoryTemplate::parse(&value)
+    }
+}
+
+impl From<DirectoryTemplate> for String {
+    fn from(value: DirectoryTemplate) -> Self {
+        value.0.source
+    }
+}
+
+/// Format a date, including its offset where known. Any offset specifiers, such as `%z`, are dropped for
+/// dates without one rather than failing.
+fn format_date(date: &CaptureTime, format: &str) -> String {
+    let mut output = String::new();
+
+    let result = match date.as_datetime() {
+        Some(datetime) => write!(output, "{}", datetime.format(format)),
+        None => {
+            let items = StrftimeItems::new(format).filter(|item| {
+                !matches!(
+                    item,
+                    Item::Fixed(
+                        Fixed::TimezoneName
+                            | Fixed::TimezoneOffset
+                            | Fixed::TimezoneOffsetColon
+                            | Fixed::TimezoneOffsetColonZ
+                            | Fixed::TimezoneOffsetDoubleColon
+                            | Fixed::TimezoneOffsetTripleColon
+                            | Fixed::TimezoneOffsetZ
+                            | Fixed::RFC2822
+                            | Fixed::RFC3339
+                    )
+                )
+            });
+
+            write!(output, "{}", date.local.format_with_items(items))
+        }
+    };
+
+    match result {
+        Ok(()) => output,
+        Err(_) => String::new(),
+    }
+}
+
+/// Camera makes and models are free text, so strip anything that would cause trouble in a filename.
+fn sanitise_component(value: &str) -> String {
+    value
+        .trim()
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+pub struct TemplateError(String);
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn render_filename(
+        template: &str,
+        counter: u32,
+        make: Option<&str>,
+        motion_photo: bool,
+    ) -> String {
+        let date = CaptureTime::naive(
+            NaiveDate::from_ymd_opt(2020, 1, 2)
+                .unwrap()
+                .and_hms_milli_opt(3, 4, 5, 678)
+                .unwrap(),
+        );
+
+        FilenameTemplate::parse(template)
+            .unwrap()
+            .render(&TemplateValues {
+                date: &date,
+                counter,
+                stem: "IMG_0001",
+                make,
+                model: Some("EOS 5D Mark II"),
+                extension: "JPG",
+                motion_photo,
+            })
+    }
+
+    #[test]
+    fn renders_filename_templates() {
+        assert_eq!(
+            render_filename(DEFAULT_FILENAME_TEMPLATE, 0, None, false),
+            "20200102_030405.JPG"
+        );
+        assert_eq!(
+            render_filename(DEFAULT_FILENAME_TEMPLATE, 2, None, true),
+            "20200102_030405.2.mp.JPG"
+        );
+        assert_eq!(
+            render_filename(
+                "{date:%Y%m%d_%H%M%S%.3f}[_{make}]_{model}[-{counter}].{ext}",
+                0,
+                Some("Canon"),
+                false
+            ),
+            "20200102_030405.678_Canon_EOS-5D-Mark-II.JPG"
+        );
+        assert_eq!(
+            render_filename(
+                "{year}-{month}-{day}[_{make}]_{stem}[-{counter}].{ext}",
+                1,
+                None,
+                false
+            ),
+            "2020-01-02_IMG_0001-1.JPG"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_filename_templates() {
+        let invalid = [
+            // Missing or hidden counter
+            "{date:%Y%m%d}.{ext}",
+            "{date:%Y%m%d}[.{make}{counter}].{ext}",
+            // Syntax errors
+            "{date:%Y%m%d[.{counter}].{ext}",
+            "{date:%Y%m%d}}[.{counter}].{ext}",
+            "{date:%Y%m%d}[[.{counter}]].{ext}",
+            "{date:%Y%m%d}].{counter}.{ext}",
+            "{date:%Y%m%d}[.{counter}.{ext}",
+            // Unknown tokens and bad date formats
+            "{date}[.{counter}].{ext}",
+            "{date:}[.{counter}].{ext}",
+            "{date:%Q}[.{counter}].{ext}",
+            "{lens}[.{counter}].{ext}",
+            // Path separators, written directly or produced by the date format
+            "{year}/{date:%m%d}[.{counter}].{ext}",
+            "{year}\\{date:%m%d}[.{counter}].{ext}",
+            "{date:%D}[.{counter}].{ext}",
+        ];
+
+        for template in invalid {
+            assert!(FilenameTemplate::parse(template).is_err(), "{}", template);
+        }
+    }
+
+    #[test]
+    fn renders_directory_templates() {
+        let date = CaptureTime::naive(
+            NaiveDate::from_ymd_opt(2020, 1, 2)
+                .unwrap()
+                .and_hms_opt(3, 4, 5)
+                .unwrap(),
+        );
+        let render = |template: &str, model: Option<&str>| {
+            DirectoryTemplate::parse(template)
+                .unwrap()
+                .render(&TemplateValues {
+                    date: &date,
+                    counter: 0,
+                    stem: "IMG_0001",
+                    make: None,
+                    model,
+                    extension: "JPG",
+                    motion_photo: false,
+                })
+        };
+
+        assert_eq!(render("", None), PathBuf::new());
+        assert_eq!(
+            render("{year}/{year}-{month}/{day}", None),
+            PathBuf::from("2020/2020-01/02")
+        );
+        assert_eq!(
+            render("{date:%Y/%m}/{model}", Some("Pixel 4a")),
+            PathBuf::from("2020/01/Pixel-4a")
+        );
+
+        // Levels which render as empty are skipped rather than left as empty directory names
+        assert_eq!(
+            render("{year}/{model}/{month}", None),
+            PathBuf::from("2020/01")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_directory_templates() {
+        let invalid = [
+            "{year}/{counter}",
+            "/{year}",
+            "{year}/../{month}",
+            "{year}\\{month}",
+            "{year}/{unknown}",
+        ];
+
+        for template in invalid {
+            assert!(DirectoryTemplate::parse(template).is_err(), "{}", template);
+        }
+    }
+
+    #[test]
+    fn filename_templates_need_an_extension() {
+        assert!(FilenameTemplate::parse("{date:%Y%m%d_%H%M%S}[.{counter}]").is_err());
+        assert!(
+            FilenameTemplate::parse("{date:%Y%m%d_%H%M%S}[.{counter}][.{make}.{ext}]").is_err()
+        );
+        assert!(FilenameTemplate::parse("{date:%Y%m%d_%H%M%S}[.{counter}][.{ext}]").is_ok());
+        assert!(FilenameTemplate::parse(DEFAULT_FILENAME_TEMPLATE).is_ok());
+    }
+}
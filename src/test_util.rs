@@ -0,0 +1,80 @@
+use std::fs;
+use std::path::PathBuf;
+
+/// Build an ISO BMFF box with a 32 bit size.
+pub(crate) fn mp4_box(box_type: &[u8; 4], contents: &[u8]) -> Vec<u8> {
+    let mut data = ((contents.len() + 8) as u32).to_be_bytes().to_vec();
+    data.extend_from_slice(box_type);
+    data.extend_from_slice(contents);
+    data
+}
+
+/// Build a little-endian TIFF with a single IFD holding the given ASCII fields. Values longer than four bytes
+/// are stored after the IFD, as usual.
+pub(crate) fn ascii_tiff(fields: &[(u16, &str)]) -> Vec<u8> {
+    let mut fields = fields.to_vec();
+    fields.sort_by_key(|(tag, _)| *tag);
+
+    let values_offset = 8 + 2 + fields.len() * 12 + 4;
+    let mut data = b"II*\0".to_vec();
+    let mut values = vec![];
+
+    data.extend_from_slice(&8u32.to_le_bytes());
+    data.extend_from_slice(&(fields.len() as u16).to_le_bytes());
+
+    for (tag, value) in fields {
+        let mut value = value.as_bytes().to_vec();
+        value.push(0);
+
+        data.extend_from_slice(&tag.to_le_bytes());
+        data.extend_from_slice(&2u16.to_le_bytes());
+        data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+
+        match value.len() {
+            0..=4 => {
+                value.resize(4, 0);
+                data.extend_from_slice(&value);
+            }
+            _ => {
+                data.extend_from_slice(&((values_offset + values.len()) as u32).to_le_bytes());
+                values.extend_from_slice(&value);
+
+                if values.len() % 2 == 1 {
+                    values.push(0);
+                }
+            }
+        }
+    }
+
+    data.extend_from_slice(&0u32.to_le_bytes());
+    data.extend_from_slice(&values);
+    data
+}
+
+/// Build a JPEG holding the given EXIF TIFF data and any extra segments, with no image data.
+pub(crate) fn jpeg(tiff: &[u8], extra_segments: &[u8]) -> Vec<u8> {
+    let mut app1 = b"Exif\0\0".to_vec();
+    app1.extend_from_slice(tiff);
+
+    let mut data = vec![0xFF, 0xD8, 0xFF, 0xE1];
+    data.extend_from_slice(&((app1.len() + 2) as u16).to_be_bytes());
+    data.extend_from_slice(&app1);
+    data.extend_from_slice(extra_segments);
+    data.extend_from_slice(&[0xFF, 0xD9]);
+    data
+}
+
+/// The directory in the temp directory that fixtures for this test run are written to.
+pub(crate) fn fixture_directory() -> PathBuf {
+    let directory =
+        std::env::temp_dir().join(format!("photo_renamer_tests_{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
+/// Write a fixture to a file of its own in the temp directory, for parsers which read from a path.
+pub(crate) fn write_fixture(name: &str, data: &[u8]) -> PathBuf {
+    let path = fixture_directory().join(name);
+    fs::write(&path, data).unwrap();
+    path
+}
@@ -0,0 +1,450 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration, FixedOffset, Local, NaiveDateTime, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde_derive::{Deserialize, Serialize};
+
+/// The time a photo or video was taken, as the wall-clock time where it was taken, along with the UTC offset
+/// in effect there if known. Sub-second precision is kept where the source provides it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CaptureTime {
+    pub local: NaiveDateTime,
+    pub offset: Option<FixedOffset>,
+}
+
+impl CaptureTime {
+    /// A wall-clock time with no known offset, as is the case for most EXIF data.
+    pub fn naive(local: NaiveDateTime) -> Self {
+        CaptureTime {
+            local,
+            offset: None,
+        }
+    }
+
+    /// A time with a known offset.
+    pub fn with_offset(local: NaiveDateTime, offset: FixedOffset) -> Self {
+        CaptureTime {
+            local,
+            offset: Some(offset),
+        }
+    }
+
+    /// A UTC time with no indication of where it was taken, as stored by video containers. This is converted
+    /// to the local time zone of this machine, which is the best guess available.
+    pub fn from_utc(utc: NaiveDateTime) -> Self {
+        let local_time = Utc.from_utc_datetime(&utc).with_timezone(&Local);
+
+        CaptureTime::with_offset(local_time.naive_local(), local_time.offset().fix())
+    }
+
+    /// This time as an absolute instant, if the offset is known.
+    pub fn as_datetime(&self) -> Option<DateTime<FixedOffset>> {
+        self.offset?.from_local_datetime(&self.local).single()
+    }
+
+    /// Convert this time into the given time zone. Times with no known offset can't be converted, so are
+    /// assumed to already be in the target zone.
+    pub fn normalise(&self, target: &TargetTimezone) -> Self {
+        let datetime = match self.as_datetime() {
+            None => return *self,
+            Some(datetime) => datetime,
+        };
+
+        let converted = match target {
+            TargetTimezone::Local => datetime.with_timezone(&Local).fixed_offset(),
+            TargetTimezone::Utc => datetime.with_timezone(&Utc).fixed_offset(),
+            TargetTimezone::Fixed(offset) => datetime.with_timezone(offset),
+            TargetTimezone::Named(tz) => datetime.with_timezone(tz).fixed_offset(),
+        };
+
+        CaptureTime::with_offset(converted.naive_local(), *converted.offset())
+    }
+
+    /// This time moved by the given amount, such as to correct a camera clock that was set wrongly. The offset
+    /// is kept as it is.
+    pub fn shifted(&self, amount: Duration) -> Self {
+        CaptureTime {
+            local: self.local.checked_add_signed(amount).unwrap_or(self.local),
+            offset: self.offset,
+        }
+    }
+
+    /// How far apart this and another time are. Where both offsets are known this is the difference between
+    /// the two instants, otherwise the difference between the two wall-clock times.
+    pub fn difference(&self, other: &CaptureTime) -> Duration {
+        match (self.as_datetime(), other.as_datetime()) {
+            (Some(datetime), Some(other_datetime)) => datetime - other_datetime,
+            _ => self.local - other.local,
+        }
+    }
+
+    /// Whether this and another time refer to the same moment, to the second. Sub-seconds and offsets are
+    /// commonly present in one file of a RAW+JPEG pair but not the other, so they're ignored here.
+    pub fn same_second(&self, other: &CaptureTime) -> bool {
+        self.local.and_utc().timestamp() == other.local.and_utc().timestamp()
+    }
+}
+
+impl fmt::Display for CaptureTime {
+    /// Formats as ISO 8601, with the offset if known.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.as_datetime() {
+            Some(datetime) => write!(f, "{}", datetime.format("%Y-%m-%dT%H:%M:%S%.f%:z")),
+            None => write!(f, "{}", self.local.format("%Y-%m-%dT%H:%M:%S%.f")),
+        }
+    }
+}
+
+/// Where a file's date was found, ordered from the most to the least reliable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DateSource {
+    /// Embedded metadata, either EXIF or QuickTime, from the file or another in its group
+    Metadata,
+    /// A date and time in the filename
+    Filename,
+    /// A full or partial date in the name of a directory holding the file
+    Directory,
+    /// The file's modified time, which may well be when it was copied rather than taken
+    Mtime,
+}
+
+impl DateSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DateSource::Metadata => "metadata",
+            DateSource::Filename => "filename",
+            DateSource::Directory => "directory",
+            DateSource::Mtime => "mtime",
+        }
+    }
+}
+
+impl fmt::Display for DateSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for DateSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "metadata" => Ok(DateSource::Metadata),
+            "filename" => Ok(DateSource::Filename),
+            "directory" => Ok(DateSource::Directory),
+            "mtime" => Ok(DateSource::Mtime),
+            _ => Err(format!(
+                "unknown date source '{}', expected one of metadata, filename, directory or mtime",
+                s
+            )),
+        }
+    }
+}
+
+/// An EXIF field, or pair of fields, that a date can be read from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExifDateField {
+    /// When the photo was taken
+    DateTimeOriginal,
+    /// When the photo was digitised, as written by scanners
+    DateTimeDigitized,
+    /// When the file was last changed, as written by some editors
+    DateTime,
+    /// `GPSDateStamp` and `GPSTimeStamp`, which are in UTC
+    #[serde(rename = "GPS")]
+    Gps,
+}
+
+/// The EXIF fields used when none are specified in the config, in order of preference.
+pub fn default_exif_date_fields() -> Vec<ExifDateField> {
+    vec![
+        ExifDateField::DateTimeOriginal,
+        ExifDateField::DateTimeDigitized,
+        ExifDateField::DateTime,
+        ExifDateField::Gps,
+    ]
+}
+
+/// Parse a UTC offset in the `+HH:MM` form used by EXIF, or `Z`.
+pub fn parse_offset(value: &str) -> Option<FixedOffset> {
+    let value = value.trim().trim_end_matches('\0');
+
+    if value == "Z" {
+        return Some(Utc.fix());
+    }
+
+    let sign = match value.get(0..1)? {
+        "+" => 1,
+        "-" => -1,
+        _ => return None,
+    };
+
+    // Values come from lossily decoded metadata, so only slice them once they're known to be ASCII
+    if !value.is_ascii() {
+        return None;
+    }
+
+    let (hours, minutes) = match value[1..].split_once(':') {
+        Some((hours, minutes)) => (hours, minutes),
+        None if value.len() == 5 => (&value[1..3], &value[3..5]),
+        None => (&value[1..], "0"),
+    };
+
+    let seconds = hours
+        .parse::<i32>()
+        .ok()?
+        .checked_mul(3600)?
+        .checked_add(minutes.parse::<i32>().ok()?.checked_mul(60)?)?;
+
+    FixedOffset::east_opt(sign * seconds)
+}
+
+/// A time zone that all resolved times can be converted into before naming.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum TargetTimezone {
+    /// The time zone of this machine
+    Local,
+    Utc,
+    Fixed(FixedOffset),
+    /// An IANA time zone name, such as `Europe/London`
+    Named(Tz),
+}
+
+impl FromStr for TargetTimezone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "local" | "Local" => Ok(TargetTimezone::Local),
+            "UTC" | "utc" | "Z" => Ok(TargetTimezone::Utc),
+            _ => {
+                if let Some(offset) = parse_offset(s) {
+                    return Ok(TargetTimezone::Fixed(offset));
+                }
+
+                s.parse::<Tz>().map(TargetTimezone::Named).map_err(|_| {
+                    format!(
+                        "unknown time zone '{}', expected local, UTC, an offset such as +01:00 or an IANA name such as Europe/London",
+                        s
+                    )
+                })
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for TargetTimezone {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<TargetTimezone> for String {
+    fn from(value: TargetTimezone) -> Self {
+        value.to_string()
+    }
+}
+
+impl fmt::Display for TargetTimezone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetTimezone::Local => f.write_str("local"),
+            TargetTimezone::Utc => f.write_str("UTC"),
+            TargetTimezone::Fixed(offset) => write!(f, "{}", offset),
+            TargetTimezone::Named(tz) => f.write_str(tz.name()),
+        }
+    }
+}
+
+/// A length of time in the config, such as a correction to a camera's clock, written either as `+HH:MM:SS`
+/// or with units such as `-1h` or `+3m12s`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ConfigDuration(pub Duration);
+
+impl FromStr for ConfigDuration {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid duration '{}', expected something like +00:03:12 or -1h",
+                s
+            )
+        };
+
+        let value = s.trim();
+        let (sign, value) = match value.get(0..1) {
+            Some("-") => (-1, &value[1..]),
+            Some("+") => (1, &value[1..]),
+            _ => (1, value),
+        };
+
+        if value.is_empty() {
+            return Err(invalid());
+        }
+
+        // Adds a number of units to a running total, failing on anything too large to be a useful duration
+        let add = |seconds: i64, amount: &str, unit: i64| -> Result<i64, String> {
+            amount
+                .parse::<i64>()
+                .ok()
+                .and_then(|amount| amount.checked_mul(unit))
+                .and_then(|amount| seconds.checked_add(amount))
+                .ok_or_else(invalid)
+        };
+
+        let mut seconds: i64 = 0;
+
+        if value.contains(':') {
+            let parts = value.split(':').collect::<Vec<&str>>();
+
+            if parts.len() > 3 {
+                return Err(invalid());
+            }
+
+            // Missing trailing parts are treated as zero, so `+01:30` is an hour and a half
+            for (part, unit) in parts.iter().zip([3600, 60, 1]) {
+                seconds = add(seconds, part, unit)?;
+            }
+        } else {
+            let mut digits = String::new();
+
+            for c in value.chars() {
+                if c.is_ascii_digit() {
+                    digits.push(c);
+                    continue;
+                }
+
+                let unit = match c.to_ascii_lowercase() {
+                    'd' => 86400,
+                    'h' => 3600,
+                    'm' => 60,
+                    's' => 1,
+                    _ => return Err(invalid()),
+                };
+
+                seconds = add(seconds, &digits, unit)?;
+                digits.clear();
+            }
+
+            // A bare number is taken as seconds
+            if !digits.is_empty() {
+                seconds = add(seconds, &digits, 1)?;
+            }
+        }
+
+        Duration::try_seconds(sign * seconds)
+            .map(ConfigDuration)
+            .ok_or_else(invalid)
+    }
+}
+
+impl TryFrom<String> for ConfigDuration {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<ConfigDuration> for String {
+    fn from(value: ConfigDuration) -> Self {
+        value.to_string()
+    }
+}
+
+impl fmt::Display for ConfigDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seconds = self.0.num_seconds();
+        let sign = if seconds < 0 { '-' } else { '+' };
+        let seconds = seconds.abs();
+
+        write!(
+            f,
+            "{}{:02}:{:02}:{:02}",
+            sign,
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_offsets() {
+        let offset = |seconds| FixedOffset::east_opt(seconds);
+
+        assert_eq!(parse_offset("+09:00"), offset(9 * 3600));
+        assert_eq!(parse_offset("-05:30\0"), offset(-(5 * 3600 + 30 * 60)));
+        assert_eq!(parse_offset(" +0100 "), offset(3600));
+        assert_eq!(parse_offset("-3"), offset(-3 * 3600));
+        assert_eq!(parse_offset("Z"), offset(0));
+    }
+
+    #[test]
+    fn rejects_malformed_offsets() {
+        assert_eq!(parse_offset(""), None);
+        assert_eq!(parse_offset("09:00"), None);
+        assert_eq!(parse_offset("+\u{ff}0"), None);
+        assert_eq!(parse_offset("+ab:cd"), None);
+        assert_eq!(parse_offset("+99999999:00"), None);
+        assert_eq!(parse_offset("+25:00"), None);
+    }
+
+    fn duration(value: &str) -> Result<i64, String> {
+        value
+            .parse::<ConfigDuration>()
+            .map(|duration| duration.0.num_seconds())
+    }
+
+    #[test]
+    fn parses_clock_durations() {
+        assert_eq!(duration("+00:03:12"), Ok(192));
+        assert_eq!(duration("-01:00:00"), Ok(-3600));
+        assert_eq!(duration("01:30"), Ok(5400));
+        assert!(duration("1:2:3:4").is_err());
+    }
+
+    #[test]
+    fn parses_durations_with_units() {
+        assert_eq!(duration("-1h"), Ok(-3600));
+        assert_eq!(duration("+3m12s"), Ok(192));
+        assert_eq!(duration("2D"), Ok(172800));
+        assert_eq!(duration("90"), Ok(90));
+        assert!(duration("").is_err());
+        assert!(duration("-").is_err());
+        assert!(duration("h").is_err());
+        assert!(duration("1w").is_err());
+    }
+
+    #[test]
+    fn rejects_durations_that_overflow() {
+        assert!(duration("99999999999999999:00:00").is_err());
+        assert!(duration("999999999999999h").is_err());
+        assert!(duration("-9999999999999999s").is_err());
+        assert!(duration("99999999999999999999").is_err());
+    }
+
+    #[test]
+    fn displays_durations_as_clock_times() {
+        assert_eq!(
+            ConfigDuration(Duration::seconds(192)).to_string(),
+            "+00:03:12"
+        );
+        assert_eq!(
+            ConfigDuration(Duration::seconds(-90061)).to_string(),
+            "-25:01:01"
+        );
+    }
+}